@@ -11,6 +11,8 @@ then
 fi
 
 TOOLCHAIN=nightly
+# How long each command may take, in seconds. The host can change it for each crate.
+TIMEOUT=3600
 
 export CARGO_INCREMENTAL=0
 export RUST_BACKTRACE=1
@@ -38,8 +40,9 @@ elif [[ $TOOL == "check" ]]; then
 fi
 export RUSTDOCFLAGS=$RUSTFLAGS
 
+# src/repro.rs writes scripts that run the same commands as these, so keep them in sync
 function timed {
-    timeout --kill-after=10s 1h inapty cargo +$TOOLCHAIN "$@" --target=$TARGET
+    timeout --kill-after=10s ${TIMEOUT}s inapty cargo +$TOOLCHAIN "$@" --target=$TARGET
 }
 
 function run_build {
@@ -64,18 +67,68 @@ function run_miri {
     timed miri test --doc --no-fail-fast $ARGS
 }
 
+# Both build a sysroot, which needs the network, so they can't wait for a crate
+function setup {
+    if [[ $TOOL == "miri" ]]; then
+        timed miri setup &> /dev/null
+    elif [[ $TOOL == "asan" ]]; then
+        timed careful setup &> /dev/null
+    fi
+}
+
+# Downloads a crate and everything it needs to build into /build and the registry, and sets ARGS
+function fetch {
+    # Delete everything in our writable mount points
+    find /build /tmp /root/.cargo/registry -mindepth 1 -delete
+    cd /build
+    cargo download $1 /build || return 1
+    ARGS=$(get-args $1)
+    cargo update &> /dev/null
+    timed fetch &> /dev/null
+}
+
+# `run.sh fetch <crate> <timeout>` only fetches, in a sandbox of its own that has the network, for
+# workers that run with FETCH_SEPARATELY because they can never be taken off it. The host passes
+# the ARGS printed here on to the worker.
+if [[ ${1:-} == "fetch" ]]; then
+    TIMEOUT=${3:-3600}
+    setup
+    if fetch $2; then
+        echo "-${TEST_END_DELIMITER}-args-"
+        echo "$ARGS"
+    fi
+    exit 0
+fi
+
+if [[ -z ${FETCH_SEPARATELY:-} ]]; then
+    setup
+fi
+
+# The exact versions, for the record. The host records which dated nightly this is.
+TOOLCHAIN_VERSION=$(rustc +$TOOLCHAIN -vV)
 if [[ $TOOL == "miri" ]]; then
-    timed miri setup &> /dev/null
+    TOOLCHAIN_VERSION="$TOOLCHAIN_VERSION
+$(cargo +$TOOLCHAIN miri --version)"
 fi
 
-while read crate;
+while read crate timeout args;
 do
-    cd /build
-    # Delete everything in our writable mount points
-    find /build /tmp /root/.cargo/registry -mindepth 1 -delete
-    if cargo download $crate /build; then
-        ARGS=$(get-args $crate)
-        cargo update &> /dev/null
+    TIMEOUT=${timeout:-3600}
+    fetched=false
+    if [[ -n ${FETCH_SEPARATELY:-} ]]; then
+        # `run.sh fetch` already put the crate in /build, if it could
+        ARGS=$args
+        [[ -e /build/Cargo.toml ]] && fetched=true
+    elif fetch $crate; then
+        # Everything that needs the network happens before this. The host takes the network away
+        # when it sees the marker, then tells us to go on.
+        echo "-${TEST_END_DELIMITER}-fetched-"
+        read -r
+        fetched=true
+    fi
+    if $fetched; then
+        cd /build
+        export CARGO_NET_OFFLINE=true
         if [[ $TOOL == "build" ]]; then
             run_build
         elif [[ $TOOL == "check" ]]; then
@@ -87,7 +140,16 @@ do
         else
             exit 1
         fi
+        # Report what the result depends on, so that it can be reproduced later
+        echo "-${TEST_END_DELIMITER}-args-"
+        echo "$ARGS"
+        echo "-${TEST_END_DELIMITER}-env-"
+        echo "$TOOLCHAIN_VERSION" | sed 's/^/# /'
+        export -p | grep -E '^declare -x (TARGET|CARGO_INCREMENTAL|RUST_BACKTRACE|RUSTFLAGS|RUSTDOCFLAGS|MIRIFLAGS|ASAN_OPTIONS|RUST_MIN_STACK)='
+        echo "-${TEST_END_DELIMITER}-lockfile-"
+        cat Cargo.lock 2> /dev/null
     fi
+    unset CARGO_NET_OFFLINE
     echo "-${TEST_END_DELIMITER}-"
     # Delete everything in our writable mount points
     find /build /tmp /root/.cargo/registry -mindepth 1 -delete
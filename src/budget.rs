@@ -0,0 +1,147 @@
+//! How much memory and time a crate is allowed, and giving more to crates that ran out.
+
+use crate::{Crate, Status};
+use color_eyre::Result;
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Budget {
+    pub memory_gb: usize,
+    /// For each command `docker/run.sh` runs
+    pub timeout_secs: u64,
+}
+
+/// The most a crate will be given by escalation. Overrides can go past these.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub base: Budget,
+    pub max: Budget,
+}
+
+impl Budget {
+    /// A bigger budget for a crate that ran out of this one, if it is allowed one. `status` is
+    /// the diagnosis of the run, and the kinds here are the ones `rules.json` uses.
+    pub fn escalate(self, status: &Status, limits: &Limits) -> Option<Self> {
+        let Status::Error(kind) = status else {
+            return None;
+        };
+        let mut escalated = self;
+        match kind.as_str() {
+            "OOM" => {
+                escalated.memory_gb = (self.memory_gb * 2).min(limits.max.memory_gb);
+            }
+            "Timeout" => {
+                escalated.timeout_secs = (self.timeout_secs * 2).min(limits.max.timeout_secs);
+            }
+            // Not "Hung": the host's deadline grows with the budget, so a crate that hangs would
+            // only hold its worker for longer each time
+            _ => return None,
+        }
+        (escalated != self
+            && escalated.memory_gb >= self.memory_gb
+            && escalated.timeout_secs >= self.timeout_secs)
+            .then_some(escalated)
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}g, {}s", self.memory_gb, self.timeout_secs)
+    }
+}
+
+/// The budgets of crates that are known to need more than the base budget, read from a JSON file
+/// like `{"some-crate": {"memory_gb": 32, "timeout_secs": 7200}}`. Either field can be left out.
+#[derive(Default)]
+pub struct Overrides(HashMap<String, Override>);
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Override {
+    memory_gb: Option<usize>,
+    timeout_secs: Option<u64>,
+}
+
+impl Overrides {
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(Self(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    /// The budget to start this crate with.
+    pub fn initial(&self, krate: &Crate, limits: &Limits) -> Budget {
+        let mut budget = limits.base;
+        if let Some(o) = self.0.get(&krate.name) {
+            budget.memory_gb = o.memory_gb.unwrap_or(budget.memory_gb);
+            budget.timeout_secs = o.timeout_secs.unwrap_or(budget.timeout_secs);
+        }
+        budget
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        base: Budget {
+            memory_gb: 8,
+            timeout_secs: 100,
+        },
+        max: Budget {
+            memory_gb: 32,
+            timeout_secs: 300,
+        },
+    };
+
+    fn escalations(kind: &str) -> Vec<Budget> {
+        let status = Status::Error(kind.to_string());
+        std::iter::successors(Some(LIMITS.base), |budget| {
+            budget.escalate(&status, &LIMITS)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn memory_doubles_up_to_the_max() {
+        let memory = escalations("OOM")
+            .iter()
+            .map(|budget| budget.memory_gb)
+            .collect::<Vec<_>>();
+        assert_eq!(memory, [8, 16, 32]);
+        assert!(escalations("OOM")
+            .iter()
+            .all(|budget| budget.timeout_secs == 100));
+    }
+
+    #[test]
+    fn time_doubles_up_to_the_max() {
+        let timeouts = escalations("Timeout")
+            .iter()
+            .map(|budget| budget.timeout_secs)
+            .collect::<Vec<_>>();
+        // The last doubling is cut short by the max
+        assert_eq!(timeouts, [100, 200, 300]);
+    }
+
+    #[test]
+    fn only_running_out_is_escalated() {
+        for status in [
+            Status::Passing,
+            Status::Unknown,
+            Status::Error("Build".to_string()),
+            Status::Error("Hung".to_string()),
+        ] {
+            assert_eq!(LIMITS.base.escalate(&status, &LIMITS), None);
+        }
+    }
+
+    #[test]
+    fn overrides_past_the_max_are_not_escalated() {
+        let budget = Budget {
+            memory_gb: 64,
+            timeout_secs: 100,
+        };
+        let status = Status::Error("OOM".to_string());
+        assert_eq!(budget.escalate(&status, &LIMITS), None);
+    }
+}
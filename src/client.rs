@@ -1,5 +1,6 @@
 use crate::{Crate, Status, Tool, Version};
 use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart, Object};
+use aws_sdk_s3::types::SdkError;
 use aws_smithy_types_convert::date_time::DateTimeExt;
 use backoff::Error;
 use backoff::ExponentialBackoff;
@@ -107,10 +108,72 @@ impl Client {
         self.upload(&key, &data, "text/html;charset=utf-8").await
     }
 
+    pub async fn upload_lockfile(&self, krate: &Crate, data: Vec<u8>) -> Result<()> {
+        self.upload(&self.tool.lockfile_crate_path(krate), &data, "text/plain")
+            .await
+    }
+
+    pub async fn upload_args(&self, krate: &Crate, data: Vec<u8>) -> Result<()> {
+        self.upload(&self.tool.args_crate_path(krate), &data, "text/plain")
+            .await
+    }
+
+    pub async fn upload_env(&self, krate: &Crate, data: Vec<u8>) -> Result<()> {
+        self.upload(&self.tool.env_crate_path(krate), &data, "text/plain")
+            .await
+    }
+
+    pub async fn upload_repro(&self, krate: &Crate, data: Vec<u8>) -> Result<()> {
+        self.upload(
+            &self.tool.repro_crate_path(krate),
+            &data,
+            "text/x-shellscript",
+        )
+        .await
+    }
+
     pub async fn download_raw(&self, krate: &Crate) -> Result<Vec<u8>> {
         self.download(&self.tool.raw_crate_path(krate)).await
     }
 
+    /// The first `len` bytes of a raw log, which is enough for its provenance block without
+    /// downloading the whole log.
+    pub async fn download_raw_start(&self, krate: &Crate, len: usize) -> Result<Vec<u8>> {
+        let key = self.tool.raw_crate_path(krate);
+        retry(|| self._download_start(&key, len)).await
+    }
+
+    async fn _download_start(&self, key: &str, len: usize) -> Result<Vec<u8>> {
+        let response = self
+            .inner
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes=0-{}", len - 1))
+            .send()
+            .await?;
+        let bytes = response.body.collect().await?;
+        Ok(bytes.to_vec())
+    }
+
+    /// The `Cargo.lock` that was resolved when this crate was run, if it was saved.
+    pub async fn download_lockfile(&self, krate: &Crate) -> Result<Option<Vec<u8>>> {
+        self.download_if_exists(&self.tool.lockfile_crate_path(krate))
+            .await
+    }
+
+    /// The flags `get-args` picked when this crate was run, if they were saved.
+    pub async fn download_args(&self, krate: &Crate) -> Result<Option<Vec<u8>>> {
+        self.download_if_exists(&self.tool.args_crate_path(krate))
+            .await
+    }
+
+    /// The toolchain and environment variables the crate was run with, if they were saved.
+    pub async fn download_env(&self, krate: &Crate) -> Result<Option<Vec<u8>>> {
+        self.download_if_exists(&self.tool.env_crate_path(krate))
+            .await
+    }
+
     pub async fn download_html(&self, krate: &Crate) -> Result<Vec<u8>> {
         self.download(&self.tool.rendered_crate_path(krate)).await
     }
@@ -131,6 +194,28 @@ impl Client {
         Ok(bytes.to_vec())
     }
 
+    /// Like `download`, but a missing key is not an error and is not retried.
+    pub async fn download_if_exists(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        retry(|| self._download_if_exists(key)).await
+    }
+
+    async fn _download_if_exists(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self
+            .inner
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let bytes = response.body.collect().await?;
+        Ok(Some(bytes.to_vec()))
+    }
+
     pub async fn get_crate_downloads(&self) -> Result<HashMap<String, Option<u64>>> {
         let response = self
             .inner
@@ -0,0 +1,250 @@
+//! Which CPUs each worker is pinned to. A worker's CPUs are all on one NUMA node when they fit,
+//! and its memory is allocated on the nodes of its CPUs, so that a crate never has its threads
+//! and its memory on different sockets.
+
+use color_eyre::eyre::{eyre, Result};
+use std::{fmt, fs};
+
+/// A set of CPUs, in the order they are handed out.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cpus {
+    cpus: Vec<usize>,
+    /// The NUMA nodes they are on, or none if we don't know them
+    nodes: Vec<usize>,
+}
+
+impl Cpus {
+    /// The NUMA nodes to allocate memory on, written like the CPUs for `--cpuset-mems`.
+    pub fn nodes(&self) -> Option<String> {
+        (!self.nodes.is_empty()).then(|| format_list(&self.nodes))
+    }
+}
+
+/// Written the way `--cpuset-cpus`, taskset and sysfs write them, like `0-3,8`.
+impl fmt::Display for Cpus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format_list(&self.cpus))
+    }
+}
+
+fn format_list(list: &[usize]) -> String {
+    let mut sorted = list.to_vec();
+    sorted.sort_unstable();
+    let mut ranges = Vec::new();
+    for cpu in sorted {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == cpu => *last = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{first}-{last}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The CPUs of the host, by NUMA node.
+pub struct Layout {
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    /// `None` when we are only pretending there is one node
+    id: Option<usize>,
+    cpus: Vec<usize>,
+}
+
+impl Layout {
+    /// Reads the NUMA nodes from sysfs, or pretends there is one node on hosts that don't have
+    /// them there.
+    pub fn detect() -> Layout {
+        match read_nodes() {
+            Ok(nodes) if !nodes.is_empty() => Layout { nodes },
+            result => {
+                if let Err(e) = result {
+                    log::warn!("Couldn't read the NUMA nodes, assuming there is one: {}", e);
+                }
+                Layout {
+                    nodes: vec![Node {
+                        id: None,
+                        cpus: (0..num_cpus::get()).collect(),
+                    }],
+                }
+            }
+        }
+    }
+
+    /// Splits the CPUs into sets of `per_job`, one for each worker. A set is only spread over
+    /// more than one node when no node has that many CPUs, and CPUs that are left over on a node
+    /// are not used.
+    pub fn slots(&self, per_job: usize) -> Vec<Cpus> {
+        let per_job = per_job.max(1);
+        let chunks = |cpus: &[usize]| {
+            cpus.chunks_exact(per_job)
+                .map(|chunk| self.cpus(chunk.to_vec()))
+                .collect::<Vec<_>>()
+        };
+        if self.nodes.iter().any(|node| node.cpus.len() >= per_job) {
+            self.nodes
+                .iter()
+                .flat_map(|node| chunks(&node.cpus))
+                .collect()
+        } else {
+            let all = self
+                .nodes
+                .iter()
+                .flat_map(|node| node.cpus.iter().copied())
+                .collect::<Vec<_>>();
+            chunks(&all)
+        }
+    }
+
+    /// `slot` and the CPUs after it on the same node, wrapping around, up to `count` CPUs. These
+    /// are shared with the workers that the CPUs belong to.
+    pub fn widen(&self, slot: &Cpus, count: usize) -> Cpus {
+        let Some(first) = slot.cpus.first() else {
+            return slot.clone();
+        };
+        let Some(node) = self.nodes.iter().find(|node| node.cpus.contains(first)) else {
+            return slot.clone();
+        };
+        if count <= slot.cpus.len() || node.cpus.len() <= slot.cpus.len() {
+            return slot.clone();
+        }
+        let start = node.cpus.iter().position(|cpu| cpu == first).unwrap();
+        let mut cpus = slot.cpus.clone();
+        for cpu in node.cpus.iter().cycle().skip(start) {
+            if cpus.len() >= count.min(node.cpus.len()) {
+                break;
+            }
+            if !cpus.contains(cpu) {
+                cpus.push(*cpu);
+            }
+        }
+        self.cpus(cpus)
+    }
+
+    /// `cpus`, with the nodes they are on.
+    fn cpus(&self, cpus: Vec<usize>) -> Cpus {
+        let nodes = self
+            .nodes
+            .iter()
+            .filter(|node| node.cpus.iter().any(|cpu| cpus.contains(cpu)))
+            .map(|node| node.id)
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+        Cpus { cpus, nodes }
+    }
+}
+
+fn read_nodes() -> Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir("/sys/devices/system/node")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(Ok(id)) = name.strip_prefix("node").map(str::parse::<usize>) else {
+            continue;
+        };
+        let cpus = parse_list(&fs::read_to_string(entry.path().join("cpulist"))?)?;
+        // Nodes with only memory have none
+        if !cpus.is_empty() {
+            nodes.push(Node { id: Some(id), cpus });
+        }
+    }
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// Parses a list like `0-3,8`.
+fn parse_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let (Ok(first), Ok(last)) = (first.parse::<usize>(), last.parse::<usize>()) else {
+            return Err(eyre!("Couldn't parse the CPU list {}", list));
+        };
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two nodes, with some CPUs of the first one numbered after the second, like hyperthreads
+    /// are on many hosts.
+    fn layout() -> Layout {
+        Layout {
+            nodes: vec![
+                Node {
+                    id: Some(0),
+                    cpus: parse_list("0-3,8-9").unwrap(),
+                },
+                Node {
+                    id: Some(1),
+                    cpus: parse_list("4-7").unwrap(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn parse_lists() {
+        assert_eq!(parse_list("0-3,8\n").unwrap(), [0, 1, 2, 3, 8]);
+        assert_eq!(parse_list("5").unwrap(), [5]);
+        assert!(parse_list("\n").unwrap().is_empty());
+        assert!(parse_list("0-x").is_err());
+    }
+
+    #[test]
+    fn slots_stay_within_a_node() {
+        let slots = layout().slots(2);
+        let written = slots.iter().map(Cpus::to_string).collect::<Vec<_>>();
+        assert_eq!(written, ["0-1", "2-3", "8-9", "4-5", "6-7"]);
+        let nodes = slots.iter().map(Cpus::nodes).collect::<Vec<_>>();
+        assert_eq!(nodes[2].as_deref(), Some("0"));
+        assert_eq!(nodes[3].as_deref(), Some("1"));
+
+        // The two CPUs left over on the first node are not used
+        assert_eq!(layout().slots(4).len(), 2);
+    }
+
+    #[test]
+    fn slots_bigger_than_a_node_span_nodes() {
+        let slots = layout().slots(8);
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].to_string(), "0-5,8-9");
+        assert_eq!(slots[0].nodes().as_deref(), Some("0-1"));
+    }
+
+    #[test]
+    fn widen_wraps_around_its_node() {
+        let layout = layout();
+        let slots = layout.slots(2);
+        assert_eq!(layout.widen(&slots[2], 4).to_string(), "0-1,8-9");
+        assert_eq!(layout.widen(&slots[2], 4).nodes().as_deref(), Some("0"));
+        // No further than the node
+        assert_eq!(layout.widen(&slots[3], 8).to_string(), "4-7");
+        // Never narrower
+        assert_eq!(layout.widen(&slots[0], 1), slots[0]);
+    }
+
+    #[test]
+    fn unknown_nodes_are_not_passed_on() {
+        let layout = Layout {
+            nodes: vec![Node {
+                id: None,
+                cpus: (0..4).collect(),
+            }],
+        };
+        assert_eq!(layout.slots(2)[0].nodes(), None);
+    }
+}
@@ -0,0 +1,354 @@
+//! What we know about the versions of dependencies that UB was found in.
+
+use crate::{Crate, Status, Version};
+use std::collections::{BTreeSet, HashMap};
+
+/// Splits the name of a directory in the Cargo registry, like `foo-bar-1.0.0-rc.1`, into the
+/// crate name and version. Both may contain `-`, but only the version parses as semver.
+pub fn split_crate_dir(dir: &str) -> Option<(&str, &str)> {
+    dir.match_indices('-').find_map(|(i, _)| {
+        let (name, version) = (&dir[..i], &dir[i + 1..]);
+        semver::Version::parse(version)
+            .ok()
+            .map(|_| (name, version))
+    })
+}
+
+pub struct LockedPackage {
+    pub name: String,
+    pub version: Version,
+    /// Whether this came from crates.io, as opposed to being the crate itself
+    pub from_registry: bool,
+    /// Each is either `name` or `name version`, the latter if the name is ambiguous
+    pub dependencies: Vec<String>,
+}
+
+pub struct Lockfile {
+    pub packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+    /// A line-based reader for the subset of TOML that Cargo writes, which is enough to avoid
+    /// pulling in a TOML parser.
+    pub fn parse(lockfile: &str) -> Self {
+        let mut packages = Vec::new();
+        let mut current: Option<LockedPackage> = None;
+        let mut in_dependencies = false;
+        for line in lockfile.lines() {
+            let line = line.trim();
+            if line == "[[package]]" {
+                packages.extend(current.take());
+                current = Some(LockedPackage {
+                    name: String::new(),
+                    version: Version::parse(""),
+                    from_registry: false,
+                    dependencies: Vec::new(),
+                });
+                continue;
+            }
+            let Some(package) = current.as_mut() else {
+                continue;
+            };
+            if in_dependencies {
+                if line == "]" {
+                    in_dependencies = false;
+                } else {
+                    let dependency = line.trim_end_matches(',').trim_matches('"');
+                    package.dependencies.push(dependency.to_string());
+                }
+            } else if let Some((key, value)) = line.split_once(" = ") {
+                let value = value.trim_matches('"');
+                match key {
+                    "name" => package.name = value.to_string(),
+                    "version" => package.version = Version::parse(value),
+                    "source" => package.from_registry = value.starts_with("registry+"),
+                    "dependencies" => in_dependencies = value == "[",
+                    _ => {}
+                }
+            } else if line.starts_with('[') {
+                // Some other table, like [metadata]
+                packages.extend(current.take());
+            }
+        }
+        packages.extend(current);
+        Self { packages }
+    }
+
+    /// The packages that were downloaded from crates.io.
+    pub fn registry_packages(&self) -> impl Iterator<Item = &LockedPackage> {
+        self.packages.iter().filter(|p| p.from_registry)
+    }
+
+    /// The versions that the direct dependencies of this crate resolved to.
+    pub fn direct_dependencies(&self, krate: &str) -> Vec<&LockedPackage> {
+        let Some(root) = self
+            .packages
+            .iter()
+            .find(|p| p.name == krate && !p.from_registry)
+        else {
+            return Vec::new();
+        };
+        root.dependencies
+            .iter()
+            .filter_map(|dependency| {
+                let mut it = dependency.split(' ');
+                let name = it.next()?;
+                let version = it.next().map(Version::parse);
+                self.registry_packages()
+                    .find(|p| p.name == name && version.as_ref().is_none_or(|v| *v == p.version))
+            })
+            .collect()
+    }
+}
+
+/// Which versions of each dependency UB has been attributed to, and which versions a crate that
+/// hit that UB has passed with since.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct History {
+    bad: HashMap<String, BTreeSet<Version>>,
+    /// For each dependency, the crates that UB was attributed to it in
+    #[serde(default)]
+    implicated_by: HashMap<String, BTreeSet<String>>,
+    /// Versions that some crate in `implicated_by` passed with. Just being in the lockfile of a
+    /// passing crate doesn't mean much, since most crates don't reach the code with the UB.
+    #[serde(default)]
+    fixed: HashMap<String, BTreeSet<Version>>,
+}
+
+impl History {
+    /// Learns from results in the order they were published in, so that a crate which hit UB is
+    /// seen before the later versions of it that passed.
+    pub fn record_all<'a>(
+        &mut self,
+        results: impl IntoIterator<Item = (&'a Crate, Option<&'a Lockfile>)>,
+    ) {
+        let mut results = results.into_iter().collect::<Vec<_>>();
+        results.sort_by(|(a, _), (b, _)| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+        for (krate, lockfile) in results {
+            self.record(krate, lockfile);
+        }
+    }
+
+    /// Learns from one result. `lockfile` is what that crate's dependencies resolved to, if we
+    /// saved it.
+    pub fn record(&mut self, krate: &Crate, lockfile: Option<&Lockfile>) {
+        match &krate.status {
+            Status::UB { cause } => {
+                for cause in cause {
+                    let (name, version) = match (&cause.source_crate, &cause.source_version) {
+                        (Some(name), Some(version)) => (name, Version::parse(version)),
+                        (Some(_), None) => continue,
+                        // The UB is in the crate under test
+                        (None, _) => (&krate.name, krate.version.clone()),
+                    };
+                    self.bad.entry(name.clone()).or_default().insert(version);
+                    self.implicated_by
+                        .entry(name.clone())
+                        .or_default()
+                        .insert(krate.name.clone());
+                }
+            }
+            Status::Passing => {
+                let packages = lockfile
+                    .iter()
+                    .flat_map(|l| l.registry_packages())
+                    .map(|p| (&p.name, &p.version));
+                for (name, version) in packages.chain([(&krate.name, &krate.version)]) {
+                    let hit_it = self
+                        .implicated_by
+                        .get(name)
+                        .is_some_and(|crates| crates.contains(&krate.name));
+                    if hit_it {
+                        self.fixed
+                            .entry(name.clone())
+                            .or_default()
+                            .insert(version.clone());
+                    }
+                }
+            }
+            // Some tests didn't run, so this doesn't show the UB is gone
+            _ => {}
+        }
+    }
+
+    /// The oldest version of this dependency after `version` that a crate which hit UB in it has
+    /// passed with, after which no version has been seen with UB.
+    pub fn fixed_in(&self, name: &str, version: &str) -> Option<&Version> {
+        let version = Version::parse(version);
+        let bad = self.bad.get(name);
+        let newest_bad = bad.and_then(|bad| bad.last()).unwrap_or(&version);
+        self.fixed
+            .get(name)?
+            .iter()
+            .find(|fixed| *fixed > newest_bad && *fixed > &version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cause;
+
+    const LOCKFILE: &str = r#"# This file is automatically @generated by Cargo.
+# It is not intended for manual editing.
+version = 3
+
+[[package]]
+name = "arena"
+version = "0.3.1"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "0123456789abcdef"
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "rand"
+version = "0.8.5"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "tree"
+version = "1.2.0"
+dependencies = [
+ "arena",
+ "rand 0.8.5",
+]
+
+[metadata]
+"checksum arena 0.3.1" = "0123456789abcdef"
+"#;
+
+    fn krate(name: &str, version: &str, status: Status) -> Crate {
+        Crate {
+            name: name.to_string(),
+            version: Version::parse(version),
+            recent_downloads: None,
+            status,
+        }
+    }
+
+    fn ub_in(name: &str, version: &str) -> Status {
+        Status::UB {
+            cause: vec![Cause {
+                kind: String::from("uninitialized memory"),
+                source_crate: Some(name.to_string()),
+                source_version: Some(version.to_string()),
+                frame: None,
+            }],
+        }
+    }
+
+    fn lockfile(arena: &str) -> Lockfile {
+        Lockfile::parse(&LOCKFILE.replace("0.3.1", arena))
+    }
+
+    #[test]
+    fn parse_lockfile() {
+        let lockfile = Lockfile::parse(LOCKFILE);
+        let packages = lockfile
+            .packages
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.to_string(), p.from_registry))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            packages,
+            [
+                ("arena", String::from("0.3.1"), true),
+                ("rand", String::from("0.7.3"), true),
+                ("rand", String::from("0.8.5"), true),
+                ("tree", String::from("1.2.0"), false),
+            ]
+        );
+        assert_eq!(lockfile.registry_packages().count(), 3);
+
+        // The version picks between the two rands
+        let direct = lockfile
+            .direct_dependencies("tree")
+            .into_iter()
+            .map(|p| (p.name.as_str(), p.version.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            direct,
+            [
+                ("arena", String::from("0.3.1")),
+                ("rand", String::from("0.8.5"))
+            ]
+        );
+        assert!(lockfile.direct_dependencies("arena").is_empty());
+    }
+
+    #[test]
+    fn split_registry_dirs() {
+        assert_eq!(split_crate_dir("arena-0.3.1"), Some(("arena", "0.3.1")));
+        assert_eq!(
+            split_crate_dir("foo-bar-1.0.0-rc.1"),
+            Some(("foo-bar", "1.0.0-rc.1"))
+        );
+        assert_eq!(split_crate_dir("no-version"), None);
+    }
+
+    #[test]
+    fn fixed_when_a_crate_that_hit_the_ub_passes() {
+        let mut history = History::default();
+        let old = krate("tree", "1.2.0", ub_in("arena", "0.3.1"));
+        let new = krate("tree", "1.3.0", Status::Passing);
+        let (old_lock, new_lock) = (lockfile("0.3.1"), lockfile("0.3.2"));
+        // In the wrong order, which record_all puts right
+        history.record_all([(&new, Some(&new_lock)), (&old, Some(&old_lock))]);
+        assert_eq!(
+            history.fixed_in("arena", "0.3.1"),
+            Some(&Version::parse("0.3.2"))
+        );
+        // Nothing was learned about the dependencies without UB
+        assert_eq!(history.fixed_in("rand", "0.7.3"), None);
+    }
+
+    #[test]
+    fn other_crates_passing_does_not_fix_it() {
+        let mut history = History::default();
+        let hit = krate("tree", "1.2.0", ub_in("arena", "0.3.1"));
+        let other = krate("forest", "0.1.0", Status::Passing);
+        let (hit_lock, other_lock) = (lockfile("0.3.1"), lockfile("0.3.2"));
+        history.record_all([(&hit, Some(&hit_lock)), (&other, Some(&other_lock))]);
+        assert_eq!(history.fixed_in("arena", "0.3.1"), None);
+    }
+
+    #[test]
+    fn not_fixed_if_a_newer_version_has_ub() {
+        let mut history = History::default();
+        let results = [
+            krate("tree", "1.2.0", ub_in("arena", "0.3.1")),
+            krate("tree", "1.3.0", Status::Passing),
+            krate("leaf", "0.1.0", ub_in("arena", "0.3.3")),
+        ];
+        let locks = [lockfile("0.3.1"), lockfile("0.3.2"), lockfile("0.3.3")];
+        history.record_all(results.iter().zip(locks.iter().map(Some)));
+        assert_eq!(history.fixed_in("arena", "0.3.1"), None);
+    }
+
+    #[test]
+    fn ub_in_the_crate_itself() {
+        let mut history = History::default();
+        let old = Status::UB {
+            cause: vec![Cause {
+                kind: String::from("data race"),
+                source_crate: None,
+                source_version: None,
+                frame: None,
+            }],
+        };
+        history.record(&krate("arena", "0.3.1", old), None);
+        // Unsupported operations mean some tests didn't run
+        let unsupported = Status::Unsupported { ops: Vec::new() };
+        history.record(&krate("arena", "0.3.2", unsupported), None);
+        assert_eq!(history.fixed_in("arena", "0.3.1"), None);
+        history.record(&krate("arena", "0.3.3", Status::Passing), None);
+        assert_eq!(
+            history.fixed_in("arena", "0.3.1"),
+            Some(&Version::parse("0.3.3"))
+        );
+    }
+}
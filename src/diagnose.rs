@@ -1,97 +1,219 @@
-use crate::{Cause, Crate, Status};
+use crate::rules::{rules, Action, Rules, Stage};
+use crate::{deps, provenance};
+use crate::{Cause, Crate, Status, Tool, Unsupported};
 
+use clap::Parser;
 use color_eyre::Result;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use std::{fs, path::PathBuf};
 
 static ANSI_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new("\x1b(\\[[0-9;?]*[A-HJKSTfhilmnsu]|\\(B)").unwrap());
 
-pub fn diagnose(krate: &mut Crate, output: &[u8]) -> Result<()> {
+#[derive(Parser)]
+pub struct Args {
+    #[clap(long)]
+    tool: Tool,
+
+    /// A raw log to classify
+    log: PathBuf,
+
+    /// Add the log to the test corpus under this name, with its current diagnosis as the
+    /// expected result
+    #[clap(long)]
+    save_as: Option<String>,
+}
+
+/// Prints the diagnosis of a log on disk.
+pub fn run(args: Args) -> Result<()> {
+    let output = fs::read(&args.log)?;
+    let status = diagnose_status(rules(), args.tool, &output);
+    let expected = serde_json::to_string_pretty(&status)? + "\n";
+    print!("{}", expected);
+    if let Some(name) = args.save_as {
+        let dir = PathBuf::from("corpus").join(args.tool.to_string());
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{name}.log")), &output)?;
+        fs::write(dir.join(format!("{name}.expected")), expected)?;
+        log::info!("Saved to {}", dir.join(name).display());
+    }
+    Ok(())
+}
+
+pub fn diagnose(krate: &mut Crate, tool: Tool, output: &[u8]) -> Result<()> {
+    krate.status = diagnose_status(rules(), tool, output);
+    Ok(())
+}
+
+pub fn diagnose_status(rules: &Rules, tool: Tool, output: &[u8]) -> Status {
+    let (_, output) = provenance::split(output);
     let output = String::from_utf8_lossy(output);
-    let output = ANSI_REGEX.replace_all(&output, "").to_string();
     // Strip ANSI escape codes from the output;
-    krate.status = if output.contains("Undefined Behavior: ") {
-        Status::UB {
-            cause: diagnose_output(&output),
-        }
-    } else if output.contains("ERROR: AddressSanitizer: ") {
-        if output
-            .contains("WARNING: ASan is ignoring requested __asan_handle_no_return: stack type")
-        {
-            Status::Error("ASan false positive?".to_string())
-        } else {
-            Status::UB {
-                cause: diagnose_asan(&output),
-            }
-        }
-    } else if output.contains("SIGILL: illegal instruction") {
-        Status::UB {
-            cause: vec![Cause {
-                kind: "SIGILL".to_string(),
-                source_crate: None,
-            }],
-        }
-    } else if output.contains("misaligned pointer dereference") {
-        Status::UB {
-            cause: vec![Cause {
-                kind: "misaligned pointer dereference".to_string(),
-                source_crate: None,
-            }],
-        }
-    } else if output.contains("attempted to leave type") {
-        Status::UB {
-            cause: vec![Cause {
-                kind: "uninit type which does not permit uninit".to_string(),
-                source_crate: None,
-            }],
-        }
-    } else if output.contains("unsafe precondition(s) violated") {
-        Status::UB {
+    let output = ANSI_REGEX.replace_all(&output, "").to_string();
+    let Some(rule) = rules.find(tool, Stage::Log, &output) else {
+        return Status::Passing;
+    };
+    match rule.action {
+        Action::MiriReports => Status::UB {
+            cause: diagnose_output(rules, tool, &output),
+        },
+        Action::AsanReports => Status::UB {
+            cause: diagnose_asan(rules, tool, &output),
+        },
+        Action::UnsupportedReports => Status::Unsupported {
+            ops: diagnose_unsupported(rules, tool, &output),
+        },
+        Action::BuildFailure => match rules.find(tool, Stage::Build, &output) {
+            Some(rule) => Status::BuildFailure {
+                kind: rule.kind.clone(),
+                detail: rule.capture(&output),
+            },
+            None => Status::BuildFailure {
+                kind: String::from("other"),
+                detail: None,
+            },
+        },
+        Action::Error => Status::Error(rule.kind.clone()),
+        _ => Status::UB {
             cause: vec![Cause {
-                kind: "precondition check failed".to_string(),
+                kind: rule.kind.clone(),
                 source_crate: None,
+                source_version: None,
+                frame: None,
             }],
-        }
-    } else if output.contains("Command exited with non-zero status 124") {
-        Status::Error("Timeout".to_string())
-    } else if output.contains("Command exited with non-zero status 255") {
-        Status::Error("OOM".to_string())
-    } else if output.contains("Command exited with non-zero status") {
-        Status::Error(String::new())
-    } else {
-        Status::Passing
-    };
-    Ok(())
+        },
+    }
 }
 
-fn diagnose_asan(output: &str) -> Vec<Cause> {
+fn diagnose_asan(rules: &Rules, tool: Tool, output: &str) -> Vec<Cause> {
     let mut causes = Vec::new();
 
     let lines = output.lines().collect::<Vec<_>>();
 
-    for line in lines
+    for (l, line) in lines
         .iter()
-        .filter(|line| line.contains("ERROR: AddressSanitizer: "))
+        .enumerate()
+        .filter(|(_, line)| line.contains("ERROR: AddressSanitizer: "))
     {
-        if line.contains("requested allocation size") {
-            causes.push(Cause {
-                kind: "requested allocation size exceeds maximum supported size".to_string(),
-                source_crate: None,
-            });
+        let kind = if let Some(rule) = rules.find(tool, Stage::Asan, line) {
+            rule.kind.clone()
         } else if let Some(kind) = line.split_whitespace().nth(2) {
-            causes.push(Cause {
-                kind: kind.to_string(),
-                source_crate: None,
-            });
-        }
+            kind.to_string()
+        } else {
+            continue;
+        };
+        let (source_crate, source_version, frame) = match asan_source(&lines[l + 1..]) {
+            Some((dir, frame)) => {
+                let (name, version) = split_source_dir(dir);
+                (Some(name), version, Some(frame))
+            }
+            None => (None, None, None),
+        };
+        causes.push(Cause {
+            kind,
+            source_crate,
+            source_version,
+            frame,
+        });
     }
     causes.sort();
     causes.dedup();
     causes
 }
 
-fn diagnose_output(output: &str) -> Vec<Cause> {
+/// Nextest runs every test in its own interpreter, so each test that hits an unsupported
+/// operation reports it separately.
+fn diagnose_unsupported(rules: &Rules, tool: Tool, output: &str) -> Vec<Unsupported> {
+    let mut ops: Vec<Unsupported> = Vec::new();
+    for line in output.lines() {
+        let Some((_, message)) = line.split_once("unsupported operation: ") else {
+            continue;
+        };
+        let kind = match rules.find(tool, Stage::Unsupported, message) {
+            Some(rule) => rule.kind.clone(),
+            None => message.trim().to_string(),
+        };
+        let symbol = unsupported_symbol(message);
+        match ops
+            .iter_mut()
+            .find(|op| op.kind == kind && op.symbol == symbol)
+        {
+            Some(op) => op.tests += 1,
+            None => ops.push(Unsupported {
+                kind,
+                symbol,
+                tests: 1,
+            }),
+        }
+    }
+    ops.sort();
+    ops
+}
+
+/// Miri quotes the name of the thing it does not support, like
+/// "can't call foreign function `SSL_new` on OS `linux`", except for syscalls which are numbered:
+/// "can't execute syscall with ID 318"
+fn unsupported_symbol(message: &str) -> Option<String> {
+    let mut quoted = message.split('`');
+    if let (Some(_), Some(symbol), Some(_)) = (quoted.next(), quoted.next(), quoted.next()) {
+        return Some(symbol.to_string());
+    }
+    let (_, id) = message.split_once("with ID ")?;
+    let id = id.split_whitespace().next()?;
+    Some(id.to_string())
+}
+
+/// Walks the first stack trace of an ASan report, which looks like
+/// `#3 0x55d5c8e1a2b2 in foo::bar /root/.cargo/registry/src/index.crates.io-.../foo-1.0.0/src/x.rs:57:9`
+/// and finds the dependency the error occurred in, if it was not in the crate under test.
+/// Returns the crate and the frame within it.
+fn asan_source<'a>(lines: &[&'a str]) -> Option<(&'a str, String)> {
+    let mut in_stack = false;
+    for line in lines {
+        let line = line.trim();
+        if !line.starts_with('#') {
+            if in_stack || line.starts_with("SUMMARY: ") {
+                break;
+            }
+            // The lines between the ERROR and the stack describe the access
+            continue;
+        }
+        in_stack = true;
+        // Frames without debuginfo end in `(/path/to/lib.so+0x1234)` instead of a source path
+        let Some((rest, path)) = line.rsplit_once(char::is_whitespace) else {
+            continue;
+        };
+        if path.starts_with("/build") {
+            break;
+        } else if let Some((crate_name, file)) = registry_location(path) {
+            let function = rest.split_once(" in ").map(|(_, f)| f).unwrap_or_default();
+            return Some((crate_name, format!("{function} at {file}")));
+        }
+    }
+    None
+}
+
+/// The crate name and version from a directory in the registry. If the directory name can't be
+/// split, the whole thing is better than nothing as the name.
+fn split_source_dir(dir: &str) -> (String, Option<String>) {
+    match deps::split_crate_dir(dir) {
+        Some((name, version)) => (name.to_string(), Some(version.to_string())),
+        None => (dir.to_string(), None),
+    }
+}
+
+/// For a path into the Cargo registry like
+/// `/root/.cargo/registry/src/index.crates.io-6f17d22bba15001f/arena-0.3.1/src/lib.rs:117:13`
+/// returns the directory of the crate, `arena-0.3.1`, and the path within it, `src/lib.rs:117:13`.
+fn registry_location(path: &str) -> Option<(&str, &str)> {
+    let (_, in_registry) = path.split_once(".cargo/registry/src/")?;
+    // The first component is the registry index
+    let (_index, in_index) = in_registry.split_once('/')?;
+    in_index.split_once('/')
+}
+
+fn diagnose_output(rules: &Rules, tool: Tool, output: &str) -> Vec<Cause> {
     let mut causes = Vec::new();
 
     let lines = output.lines().collect::<Vec<_>>();
@@ -114,74 +236,61 @@ fn diagnose_output(output: &str) -> Vec<Cause> {
             })
             .unwrap_or(l + 1);
 
-        let kind;
-        if line.contains("Data race detected") {
-            kind = "data race".to_string()
-        } else if line.contains("encountered uninitialized")
-            || line.contains("this operation requires initialized memory")
-        {
-            kind = "uninitialized memory".to_string();
-        } else if line.contains("out-of-bounds") {
-            kind = "invalid pointer offset".to_string();
-        } else if line.contains("dereferencing pointer failed: null pointer is not a valid pointer")
-        {
-            kind = "null pointer dereference".to_string();
-        } else if line.contains("encountered 0, but expected something greater or equal to 1") {
-            kind = "zero-initialized nonzero type".to_string();
-        } else if line.contains("encountered a null reference") {
-            kind = "null reference".to_string();
-        } else if line.contains("accessing memory with alignment") {
-            kind = "misaligned pointer dereference".to_string();
-        } else if line.contains("dangling reference") {
-            kind = "dangling reference".to_string();
-        } else if line.contains("unaligned reference") {
-            kind = "unaligned reference".to_string();
-        } else if line.contains("incorrect layout on deallocation") {
-            kind = "incorrect layout on deallocation".to_string();
-        } else if line.contains("deallocating while") && line.contains("is strongly protected") {
-            kind = "deallocation conflict with dereferenceable".to_string();
-        } else if line.contains("which is strongly protected because it is an argument of call") {
-            kind = "protector invalidation".to_string();
-        } else if line.contains("attempting a write access")
-            && line.contains("only grants SharedReadOnly")
-        {
-            kind = "SB-write-via-&".to_string();
-        } else if line.contains("borrow stack")
-            || line.contains("reborrow")
-            || line.contains("retag")
-        {
-            if line.contains("<untagged>") {
-                kind = "int-to-ptr cast".to_string();
-            } else {
-                kind = diagnose_sb(&lines[l..end]);
+        let kind = match rules.find(tool, Stage::Miri, line) {
+            Some(rule) if rule.action == Action::StackedBorrows => {
+                match rules.find_in_report(tool, Stage::StackedBorrows, &lines[l..end]) {
+                    Some(rule) => rule.kind.clone(),
+                    None => String::from("SB-uncategorized"),
+                }
             }
-        } else if line.contains("type validation failed") {
-            let second = line.split(": encountered").nth(1).unwrap().trim();
-            kind = format!("type validation failed: encountered {}", second);
-        } else {
-            kind = line
+            Some(rule) if rule.action == Action::TypeValidation => {
+                let second = line
+                    .split(": encountered")
+                    .nth(1)
+                    .unwrap_or_default()
+                    .trim();
+                format!("type validation failed: encountered {}", second)
+            }
+            Some(rule) => rule.kind.clone(),
+            None => line
                 .split("Undefined Behavior: ")
                 .nth(1)
                 .unwrap()
                 .trim()
-                .to_string();
-        }
+                .to_string(),
+        };
 
         let mut source_crate = None;
+        let mut source_version = None;
+        let mut frame = None;
 
         for line in &lines[l..] {
             if line.contains("inside `") && line.contains(" at ") {
                 let path = line.split(" at ").nth(1).unwrap();
                 if path.starts_with("/build") || !path.starts_with('/') {
                     break;
-                } else if path.contains(".cargo/registry/src/") {
-                    let crate_name = path.split('/').nth(6).unwrap();
-                    source_crate = Some(crate_name.to_string());
+                } else if let Some((crate_name, file)) = registry_location(path) {
+                    let (name, version) = split_source_dir(crate_name);
+                    source_crate = Some(name);
+                    source_version = version;
+                    let function = line
+                        .split("inside `")
+                        .nth(1)
+                        .and_then(|f| f.split("` at ").next())
+                        .unwrap_or_default();
+                    // Miri prints the whole span, but the start is enough to identify it
+                    let file = file.split(": ").next().unwrap_or(file);
+                    frame = Some(format!("{function} at {file}"));
                     break;
                 }
             }
         }
-        causes.push(Cause { kind, source_crate })
+        causes.push(Cause {
+            kind,
+            source_crate,
+            source_version,
+            frame,
+        })
     }
 
     causes.sort();
@@ -189,25 +298,51 @@ fn diagnose_output(output: &str) -> Vec<Cause> {
     causes
 }
 
-fn diagnose_sb(lines: &[&str]) -> String {
-    if lines[0].contains("only grants SharedReadOnly") && lines[0].contains("for Unique") {
-        String::from("&->&mut")
-    } else if lines.iter().any(|line| {
-        line.contains("attempting a write access") && line.contains("only grants SharedReadOnly")
-    }) {
-        String::from("write through pointer based on &")
-    } else if lines.iter().any(|line| line.contains("invalidated")) {
-        String::from("SB-invalidation")
-    } else if lines
-        .iter()
-        .any(|line| line.contains("created due to a retag at offsets [0x0..0x0]"))
-    {
-        String::from("SB-null-provenance")
-    } else if lines[0].contains("does not exist in the borrow stack") {
-        String::from("SB-use-outside-provenance")
-    } else if lines[0].contains("no item granting write access for deallocation") {
-        String::from("SB-invalid-dealloc")
-    } else {
-        String::from("SB-uncategorized")
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+    use std::path::Path;
+
+    /// Checks every log in `corpus/<tool>/` against the `.expected` file next to it.
+    /// Run with `BLESS=1` to accept the current diagnoses.
+    #[test]
+    fn corpus() {
+        let rules = Rules::embedded();
+        let bless = std::env::var_os("BLESS").is_some();
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+        let mut failures = Vec::new();
+        for tool_dir in fs::read_dir(corpus).unwrap() {
+            let tool_dir = tool_dir.unwrap();
+            let tool: Tool = tool_dir.file_name().to_str().unwrap().parse().unwrap();
+            for log in fs::read_dir(tool_dir.path()).unwrap() {
+                let log = log.unwrap().path();
+                if log.extension().is_none_or(|ext| ext != "log") {
+                    continue;
+                }
+                let status = diagnose_status(&rules, tool, &fs::read(&log).unwrap());
+                let actual = serde_json::to_string_pretty(&status).unwrap() + "\n";
+                let expected_path = log.with_extension("expected");
+                if bless {
+                    fs::write(&expected_path, &actual).unwrap();
+                    continue;
+                }
+                let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+                if expected != actual {
+                    failures.push(format!(
+                        "{}\nexpected:\n{}actual:\n{}",
+                        log.display(),
+                        expected,
+                        actual
+                    ));
+                }
+            }
+        }
+        assert!(
+            failures.is_empty(),
+            "{} logs were diagnosed differently than expected (run with BLESS=1 to accept):\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
     }
 }
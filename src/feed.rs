@@ -0,0 +1,198 @@
+use crate::{render::escape, Cause, Crate, Status, Tool};
+use color_eyre::Result;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+use time::format_description::well_known::Rfc3339;
+
+/// How many entries we keep around in each feed
+const MAX_ENTRIES: usize = 500;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub version: String,
+    pub causes: Vec<Cause>,
+    /// RFC 3339 timestamp of the sync which first saw this crate with UB
+    pub published: String,
+}
+
+/// Produces an entry for every crate version which has UB now but did not in the previous sync.
+pub fn new_ub_entries(previous: &[Crate], current: &[Crate]) -> Result<Vec<Entry>> {
+    let previously_ub = previous
+        .iter()
+        .filter(|c| matches!(c.status, Status::UB { .. }))
+        .map(|c| (c.name.as_str(), c.version.to_string()))
+        .collect::<HashSet<_>>();
+
+    let now = time::OffsetDateTime::now_utc().format(&Rfc3339)?;
+    let mut entries = Vec::new();
+    for krate in current {
+        let Status::UB { cause } = &krate.status else {
+            continue;
+        };
+        let version = krate.version.to_string();
+        if previously_ub.contains(&(krate.name.as_str(), version.clone())) {
+            continue;
+        }
+        entries.push(Entry {
+            name: krate.name.clone(),
+            version,
+            causes: cause.clone(),
+            published: now.clone(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Adds the new entries to the front of the existing ones, dropping the oldest.
+pub fn merge_entries(new: Vec<Entry>, mut existing: Vec<Entry>) -> Vec<Entry> {
+    existing.retain(|old| {
+        !new.iter()
+            .any(|e| e.name == old.name && e.version == old.version)
+    });
+    let mut entries = new;
+    entries.extend(existing);
+    entries.truncate(MAX_ENTRIES);
+    entries
+}
+
+/// All the distinct cause kinds that appear in these entries, each of which gets its own feed.
+pub fn cause_kinds(entries: &[Entry]) -> BTreeSet<&str> {
+    entries
+        .iter()
+        .flat_map(|e| e.causes.iter().map(|c| c.kind.as_str()))
+        .collect()
+}
+
+/// Turns a cause kind into something that is safe to use as a file name.
+pub fn slug(kind: &str) -> String {
+    let mut slug = String::new();
+    for c in kind.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+pub fn render_feed(
+    tool: Tool,
+    base_url: &str,
+    kind: Option<&str>,
+    entries: &[Entry],
+) -> Result<String> {
+    let (title, self_path) = match kind {
+        Some(kind) => (format!("New {tool} UB: {kind}"), tool.cause_feed_path(kind)),
+        None => (format!("New {tool} UB"), tool.feed_path()),
+    };
+    let entries = entries
+        .iter()
+        .filter(|e| kind.is_none_or(|kind| e.causes.iter().any(|c| c.kind == kind)))
+        .collect::<Vec<_>>();
+    let updated = match entries.first() {
+        Some(e) => e.published.clone(),
+        None => time::OffsetDateTime::now_utc().format(&Rfc3339)?,
+    };
+
+    let mut output = String::new();
+    writeln!(output, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+    writeln!(output, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+    writeln!(output, "<title>{}</title>", escape(&title))?;
+    writeln!(
+        output,
+        "<id>{}/{}</id>",
+        escape(base_url),
+        escape(&self_path)
+    )?;
+    writeln!(
+        output,
+        r#"<link rel="self" href="{}/{}"/>"#,
+        escape(base_url),
+        escape(&self_path)
+    )?;
+    writeln!(
+        output,
+        r#"<link href="{}/{}"/>"#,
+        escape(base_url),
+        tool.landing_page_path()
+    )?;
+    writeln!(output, "<updated>{}</updated>", updated)?;
+    writeln!(output, "<author><name>crater-at-home</name></author>")?;
+    for e in entries {
+        let link = format!("{}/{}/logs/{}/{}", base_url, tool, e.name, e.version);
+        writeln!(output, "<entry>")?;
+        writeln!(
+            output,
+            "<title>{} {}</title>",
+            escape(&e.name),
+            escape(&e.version)
+        )?;
+        writeln!(output, "<id>{}</id>", escape(&link))?;
+        writeln!(output, r#"<link href="{}"/>"#, escape(&link))?;
+        writeln!(output, "<updated>{}</updated>", e.published)?;
+        write!(output, r#"<content type="html">&lt;ul&gt;"#)?;
+        for cause in &e.causes {
+            let mut line = escape(&cause.kind);
+            if let Some(source) = cause.source() {
+                write!(line, " ({})", escape(&source))?;
+            }
+            // The content is HTML embedded in XML, so it is escaped twice.
+            write!(output, "&lt;li&gt;{}&lt;/li&gt;", escape(&line))?;
+        }
+        writeln!(output, "&lt;/ul&gt;</content>")?;
+        writeln!(output, "</entry>")?;
+    }
+    writeln!(output, "</feed>")?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, version: &str) -> Entry {
+        Entry {
+            name: name.to_string(),
+            version: version.to_string(),
+            causes: Vec::new(),
+            published: String::new(),
+        }
+    }
+
+    #[test]
+    fn slug_collapses_punctuation() {
+        assert_eq!(slug("Stacked Borrows"), "stacked-borrows");
+        assert_eq!(slug("use-after-free (heap)"), "use-after-free-heap");
+        assert_eq!(slug("  --Data race!  "), "data-race");
+    }
+
+    #[test]
+    fn merge_puts_new_entries_first_and_replaces_old_ones() {
+        let existing = vec![entry("a", "1.0.0"), entry("b", "1.0.0")];
+        let new = vec![entry("b", "1.0.0"), entry("c", "0.1.0")];
+        let merged = merge_entries(new, existing)
+            .into_iter()
+            .map(|e| (e.name, e.version))
+            .collect::<Vec<_>>();
+        let expected = [("b", "1.0.0"), ("c", "0.1.0"), ("a", "1.0.0")]
+            .map(|(name, version)| (name.to_string(), version.to_string()));
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn merge_keeps_at_most_max_entries() {
+        let existing = (0..MAX_ENTRIES)
+            .map(|i| entry("old", &format!("0.0.{i}")))
+            .collect();
+        let merged = merge_entries(vec![entry("new", "1.0.0")], existing);
+        assert_eq!(merged.len(), MAX_ENTRIES);
+        assert_eq!(merged[0].name, "new");
+        // The oldest one is the one that is dropped
+        assert_eq!(
+            merged.last().unwrap().version,
+            format!("0.0.{}", MAX_ENTRIES - 2)
+        );
+    }
+}
@@ -1,25 +1,47 @@
 use clap::Parser;
 use color_eyre::Result;
 use diagnose::diagnose;
-use std::{fmt, str::FromStr};
+use std::{fmt, fmt::Write, path::PathBuf, str::FromStr};
 
+mod budget;
 mod client;
+mod cpus;
 mod db_dump;
+mod deps;
 mod diagnose;
+mod feed;
+mod packages;
+mod provenance;
+mod queue;
 mod render;
+mod repro;
+mod rerun;
+mod rules;
 mod run;
+mod sandbox;
+mod schedule;
 mod sync;
+mod watch;
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// A JSON file of diagnosis rules to use instead of the built-in ones
+    #[clap(long, global = true)]
+    rules: Option<PathBuf>,
 }
 
 #[derive(Parser)]
 enum Commands {
-    Run(run::Args),
+    Run(Box<run::Args>),
     Sync(sync::Args),
+    Render(render::Args),
+    Diagnose(diagnose::Args),
+    Repro(repro::Args),
+    ServeQueue(Box<queue::Args>),
+    Watch(Box<watch::Args>),
 }
 
 fn main() -> Result<()> {
@@ -33,13 +55,21 @@ fn main() -> Result<()> {
     color_eyre::install()?;
 
     let args = Cli::parse();
+    if let Some(path) = &args.rules {
+        rules::load_override(path)?;
+    }
     match args.command {
-        Commands::Run(args) => run::run(args),
+        Commands::Run(args) => run::run(*args),
         Commands::Sync(args) => sync::run(args),
+        Commands::Render(args) => render::run(args),
+        Commands::Diagnose(args) => diagnose::run(args),
+        Commands::Repro(args) => repro::run(args),
+        Commands::ServeQueue(args) => queue::serve(*args),
+        Commands::Watch(args) => watch::run(*args),
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Tool {
     Miri,
     Asan,
@@ -61,6 +91,39 @@ impl Tool {
         format!("{}/{}/{}", self.raw_path(), krate.name, krate.version)
     }
 
+    pub fn lockfile_crate_path(self, krate: &Crate) -> String {
+        format!("{}/lockfile/{}/{}", self, krate.name, krate.version)
+    }
+
+    pub fn args_crate_path(self, krate: &Crate) -> String {
+        format!("{}/args/{}/{}", self, krate.name, krate.version)
+    }
+
+    pub fn env_crate_path(self, krate: &Crate) -> String {
+        format!("{}/env/{}/{}", self, krate.name, krate.version)
+    }
+
+    pub fn repro_crate_path(self, krate: &Crate) -> String {
+        format!("{}/repro/{}/{}.sh", self, krate.name, krate.version)
+    }
+
+    pub fn oversized_path(self) -> String {
+        format!("{}/oversized.json", self)
+    }
+
+    pub fn quarantine_path(self) -> String {
+        format!("{}/quarantine.json", self)
+    }
+
+    pub fn durations_path(self) -> String {
+        format!("{}/durations.json", self)
+    }
+
+    /// The copy of the durations that the shards of a run started on `date` split the crates by
+    pub fn shard_durations_path(self, date: time::Date) -> String {
+        format!("{}/shard-durations/{}.json", self, date)
+    }
+
     pub fn html_path(self) -> &'static str {
         match self {
             Tool::Miri => "miri/logs",
@@ -74,6 +137,31 @@ impl Tool {
         format!("{}/{}/{}", self.html_path(), krate.name, krate.version)
     }
 
+    pub fn diagnoses_path(self) -> String {
+        format!("{}/diagnoses.json", self)
+    }
+
+    pub fn history_path(self) -> String {
+        format!("{}/history.json", self)
+    }
+
+    /// The crate versions `watch` has seen in the crates.io database
+    pub fn watched_path(self) -> String {
+        format!("{}/watched.json", self)
+    }
+
+    pub fn feed_entries_path(self) -> String {
+        format!("{}/feed.json", self)
+    }
+
+    pub fn feed_path(self) -> String {
+        format!("{}/feed.xml", self)
+    }
+
+    pub fn cause_feed_path(self, kind: &str) -> String {
+        format!("{}/feeds/{}.xml", self, feed::slug(kind))
+    }
+
     pub fn landing_page_path(self) -> &'static str {
         match self {
             Tool::Miri => "miri/index.html",
@@ -110,7 +198,26 @@ impl FromStr for Tool {
     }
 }
 
-#[derive(Clone, Debug)]
+impl serde::Serialize for Tool {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Tool {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Crate {
     pub name: String,
     pub version: Version,
@@ -133,6 +240,16 @@ impl serde::Serialize for Version {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for Version {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(Version::parse(&s))
+    }
+}
+
 impl Version {
     pub fn parse(s: &str) -> Self {
         semver::Version::parse(s)
@@ -150,16 +267,63 @@ impl fmt::Display for Version {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum Status {
     Unknown,
     Passing,
     Error(String),
-    UB { cause: Vec<Cause> },
+    UB {
+        cause: Vec<Cause>,
+    },
+    /// The tests ran into operations that Miri does not support, and nothing worse
+    Unsupported {
+        ops: Vec<Unsupported>,
+    },
+    /// The crate did not build; `detail` is the missing library, header, or feature if there is one
+    BuildFailure {
+        kind: String,
+        detail: Option<String>,
+    },
 }
 
-#[derive(Clone, Debug, Ord, Eq, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, Ord, Eq, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
 pub struct Cause {
     pub kind: String,
+    /// The dependency the UB happened in, if it was not the crate under test
     pub source_crate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_version: Option<String>,
+    /// The innermost frame in `source_crate`, as `function at path:line:column`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame: Option<String>,
+}
+
+impl Cause {
+    /// The name and version of the dependency the UB happened in.
+    pub fn source(&self) -> Option<String> {
+        let name = self.source_crate.as_ref()?;
+        match &self.source_version {
+            Some(version) => Some(format!("{} {}", name, version)),
+            None => Some(name.clone()),
+        }
+    }
+
+    /// Identifies the same bug showing up in the logs of different crates, which happens when
+    /// it is in a dependency they share.
+    pub fn fingerprint(&self) -> Option<String> {
+        let mut fingerprint = format!("{} in {}", self.kind, self.source()?);
+        if let Some(frame) = &self.frame {
+            write!(fingerprint, ": {}", frame).unwrap();
+        }
+        Some(fingerprint)
+    }
+}
+
+#[derive(Clone, Debug, Ord, Eq, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
+pub struct Unsupported {
+    pub kind: String,
+    /// The foreign function, syscall, or other operation that was attempted
+    pub symbol: Option<String>,
+    /// How many tests ran into this
+    pub tests: usize,
 }
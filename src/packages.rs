@@ -0,0 +1,39 @@
+//! Maps the native libraries and headers that builds fail to find onto the Ubuntu packages that
+//! provide them, so that `docker/packages.txt` can be kept up to date.
+
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+
+static KNOWN: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    serde_json::from_str(include_str!("system-packages.json"))
+        .expect("system-packages.json is invalid")
+});
+
+static INSTALLED: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    include_str!("../docker/packages.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect()
+});
+
+/// The package that probably provides this pkg-config name, linker library, or header.
+pub fn suggest(missing: &str) -> Option<String> {
+    if let Some(package) = KNOWN.get(missing) {
+        return Some(package.clone());
+    }
+    // Headers are too varied to guess at, but libraries are usually named after themselves
+    if missing.ends_with(".h") || missing.ends_with(".hpp") || missing.contains('/') {
+        return None;
+    }
+    let name = missing
+        .strip_prefix("lib")
+        .unwrap_or(missing)
+        .to_lowercase();
+    Some(format!("lib{name}-dev"))
+}
+
+/// Whether the package is already installed in our image.
+pub fn is_installed(package: &str) -> bool {
+    INSTALLED.contains(package)
+}
@@ -0,0 +1,150 @@
+//! The block at the top of every stored log that records what produced it.
+
+use crate::{budget::Budget, sandbox::Sandbox, Tool};
+use color_eyre::eyre::{eyre, Result};
+use std::{fmt::Write, sync::OnceLock};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+const START: &str = "=== crater-at-home provenance ===\n";
+const END: &str = "=== end of provenance ===\n";
+
+/// What is the same for every crate in one `run`.
+#[derive(Clone)]
+pub struct Host {
+    pub tool: Tool,
+    pub target: String,
+    /// The ID of the docker image the workers run
+    pub image: String,
+    /// The dated nightly the image runs crates with, from `toolchain`
+    pub toolchain: String,
+    pub hostname: String,
+}
+
+/// `env` is what the worker reported about its toolchain and flags, if it got far enough to.
+pub fn header(host: &Host, budget: Budget, started: OffsetDateTime, env: Option<&str>) -> String {
+    let mut header = String::from(START);
+    writeln!(header, "tool: {}", host.tool).unwrap();
+    writeln!(header, "target: {}", host.target).unwrap();
+    writeln!(header, "image: {}", host.image).unwrap();
+    writeln!(header, "toolchain: {}", host.toolchain).unwrap();
+    writeln!(header, "memory limit: {}g", budget.memory_gb).unwrap();
+    writeln!(
+        header,
+        "time limit: {}s for each command",
+        budget.timeout_secs
+    )
+    .unwrap();
+    writeln!(header, "host: {}", host.hostname).unwrap();
+    writeln!(
+        header,
+        "started: {}",
+        started.format(&Rfc3339).unwrap_or_default()
+    )
+    .unwrap();
+    if let Some(env) = env {
+        writeln!(header, "environment:").unwrap();
+        for line in env.lines() {
+            writeln!(header, "    {}", line).unwrap();
+        }
+    }
+    header.push_str(END);
+    header
+}
+
+/// Separates the provenance block, without its markers, from the rest of a log. Logs from before
+/// there was a provenance block are all log.
+pub fn split(output: &[u8]) -> (Option<String>, &[u8]) {
+    let Some(rest) = output.strip_prefix(START.as_bytes()) else {
+        return (None, output);
+    };
+    let Some(end) = rest
+        .windows(END.len())
+        .position(|window| window == END.as_bytes())
+    else {
+        return (None, output);
+    };
+    let header = String::from_utf8_lossy(&rest[..end]).into_owned();
+    (Some(header), &rest[end + END.len()..])
+}
+
+/// The toolchain recorded in a provenance block, for results since it was recorded.
+pub fn recorded_toolchain(provenance: &str) -> Option<&str> {
+    provenance
+        .lines()
+        .find_map(|line| line.strip_prefix("toolchain: "))
+        .map(str::trim)
+}
+
+static TOOLCHAIN: OnceLock<String> = OnceLock::new();
+
+/// The dated nightly that "nightly" is in the image, so that results can be reproduced with it.
+/// The image doesn't change while we run, so this only asks it once.
+pub fn toolchain(sandbox: &Sandbox) -> Result<&'static str> {
+    if let Some(toolchain) = TOOLCHAIN.get() {
+        return Ok(toolchain);
+    }
+    let output = sandbox.command("rustc", &["+nightly", "-vV"])?.output()?;
+    let version = String::from_utf8_lossy(&output.stdout);
+    let commit_date = version
+        .lines()
+        .find_map(|line| line.strip_prefix("commit-date: "))
+        .ok_or_else(|| {
+            eyre!(
+                "Couldn't find the commit date of the nightly in {}",
+                version
+            )
+        })?;
+    let mut parts = commit_date.trim().splitn(3, '-');
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) = (
+        parts.next().map(str::parse::<i32>),
+        parts.next().map(str::parse::<u8>),
+        parts.next().map(str::parse::<u8>),
+    ) else {
+        return Err(eyre!("Couldn't parse the commit date {}", commit_date));
+    };
+    // Nightlies are published the day after the commit they are built from
+    let date = time::Date::from_calendar_date(year, month.try_into()?, day)?
+        .next_day()
+        .ok_or_else(|| eyre!("There is no day after {}", commit_date))?;
+    let toolchain = format!(
+        "nightly-{}-{:02}-{:02}",
+        date.year(),
+        u8::from(date.month()),
+        date.day()
+    );
+    Ok(TOOLCHAIN.get_or_init(|| toolchain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toolchain_round_trips() {
+        let host = Host {
+            tool: Tool::Miri,
+            target: String::from("x86_64-unknown-linux-gnu"),
+            image: String::from("sha256:0123"),
+            toolchain: String::from("nightly-2026-10-18"),
+            hostname: String::from("builder"),
+        };
+        let budget = Budget {
+            memory_gb: 8,
+            timeout_secs: 3600,
+        };
+        let mut log = header(&host, budget, OffsetDateTime::UNIX_EPOCH, None).into_bytes();
+        log.extend_from_slice(b"test result: ok\n");
+
+        let (provenance, rest) = split(&log);
+        assert_eq!(rest, b"test result: ok\n");
+        let provenance = provenance.unwrap();
+        assert_eq!(recorded_toolchain(&provenance), Some("nightly-2026-10-18"));
+    }
+
+    #[test]
+    fn logs_without_provenance() {
+        let (provenance, rest) = split(b"test result: ok\n");
+        assert_eq!(provenance, None);
+        assert_eq!(rest, b"test result: ok\n");
+    }
+}
@@ -0,0 +1,705 @@
+//! Handing out crates to the workers of a run, either from a queue in this process or from a
+//! `serve-queue` coordinator that workers on any number of hosts lease crates from over HTTP.
+
+use crate::{budget::Budget, run, schedule::Progress, Crate};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Result};
+use hyper::{
+    body::to_bytes,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+/// How long a worker waits to ask again when every crate left is leased to someone else, when the
+/// coordinator can't be reached, when every crate left is backing off, or when a watched queue is
+/// empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A crate to run, and what it is allowed to use.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub krate: Crate,
+    pub budget: Budget,
+    /// How long we expect it to take, in seconds
+    pub estimate_secs: u64,
+    /// How many workers it has crashed so far
+    #[serde(default)]
+    pub crashes: usize,
+    /// The Unix time, in seconds, before which it is not handed out, to back off after a crash
+    #[serde(default)]
+    pub not_before: Option<u64>,
+}
+
+impl Job {
+    /// Whether it is not backing off at `now`.
+    fn ready(&self, now: u64) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= now)
+    }
+}
+
+/// The current Unix time, in seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Takes the next job that is not backing off from a queue in reverse order.
+fn pop_ready(queue: &mut Vec<Job>) -> Option<Job> {
+    let now = unix_now();
+    let index = queue.iter().rposition(|job| job.ready(now))?;
+    Some(queue.remove(index))
+}
+
+#[derive(Parser)]
+// The flattened `run::Args` has the same group name as this
+#[group(skip)]
+pub struct Args {
+    /// Where to listen for workers
+    #[clap(long, default_value = "0.0.0.0:8000")]
+    listen: SocketAddr,
+
+    /// How long a worker can go without a heartbeat before its crate is given to another one
+    #[clap(long, default_value_t = 5 * 60)]
+    lease_secs: u64,
+
+    /// The crates are picked and ordered the same way as by `run`
+    #[command(flatten)]
+    run: run::Args,
+}
+
+/// Serves the crates a run would run to `run --queue-url` workers until it is stopped.
+#[tokio::main]
+pub async fn serve(args: Args) -> Result<()> {
+    if args.run.rerun.needs_image() {
+        args.run.sandbox.build_image()?;
+    }
+    let queue = run::build_queue_for(&args.run).await?;
+    log::info!("Serving {} crates on {}", queue.len(), args.listen);
+    let coordinator = Arc::new(Coordinator {
+        lease: Duration::from_secs(args.lease_secs),
+        state: Mutex::new(CoordinatorState {
+            queue,
+            leases: HashMap::new(),
+            next_id: 0,
+        }),
+    });
+
+    tokio::spawn({
+        let coordinator = coordinator.clone();
+        async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                coordinator.requeue_expired();
+            }
+        }
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let coordinator = coordinator.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let coordinator = coordinator.clone();
+                async move { Ok::<_, Infallible>(coordinator.handle(request).await) }
+            }))
+        }
+    });
+    hyper::Server::bind(&args.listen)
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.unwrap();
+        })
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseRequest {
+    worker: String,
+}
+
+#[derive(Serialize, Deserialize)]
+enum LeaseResponse {
+    Leased {
+        id: u64,
+        job: Job,
+        lease_secs: u64,
+    },
+    /// Every crate left is leased or backing off, but some may come back if their worker goes
+    /// away
+    Wait,
+    Done,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HeartbeatRequest {
+    id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompleteRequest {
+    id: u64,
+    /// Run the crate again later, like this
+    retry: Option<Job>,
+}
+
+/// Gives a leased crate back, to be the next one handed out.
+#[derive(Serialize, Deserialize)]
+struct ReleaseRequest {
+    id: u64,
+}
+
+struct Coordinator {
+    lease: Duration,
+    state: Mutex<CoordinatorState>,
+}
+
+struct CoordinatorState {
+    /// In reverse order, so that crates can be popped
+    queue: Vec<Job>,
+    leases: HashMap<u64, Leased>,
+    next_id: u64,
+}
+
+struct Leased {
+    job: Job,
+    worker: String,
+    expires: Instant,
+}
+
+impl Coordinator {
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let route = (request.method().clone(), request.uri().path().to_string());
+        let body = match to_bytes(request.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return error(StatusCode::BAD_REQUEST, e),
+        };
+        match (route.0, route.1.as_str()) {
+            (Method::POST, "/lease") => match serde_json::from_slice(&body) {
+                Ok(request) => json(&self.lease(request)),
+                Err(e) => error(StatusCode::BAD_REQUEST, e),
+            },
+            (Method::POST, "/heartbeat") => match serde_json::from_slice(&body) {
+                Ok(request) => self.heartbeat(request),
+                Err(e) => error(StatusCode::BAD_REQUEST, e),
+            },
+            (Method::POST, "/complete") => match serde_json::from_slice(&body) {
+                Ok(request) => self.complete(request),
+                Err(e) => error(StatusCode::BAD_REQUEST, e),
+            },
+            (Method::POST, "/release") => match serde_json::from_slice(&body) {
+                Ok(request) => self.release(request),
+                Err(e) => error(StatusCode::BAD_REQUEST, e),
+            },
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn lease(&self, request: LeaseRequest) -> LeaseResponse {
+        let mut state = self.state.lock().unwrap();
+        let Some(job) = pop_ready(&mut state.queue) else {
+            return if state.queue.is_empty() && state.leases.is_empty() {
+                LeaseResponse::Done
+            } else {
+                LeaseResponse::Wait
+            };
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        log::info!(
+            "Leasing {} {} to {}; {} crates left, {} leased",
+            job.krate.name,
+            job.krate.version,
+            request.worker,
+            state.queue.len(),
+            state.leases.len() + 1
+        );
+        let response = LeaseResponse::Leased {
+            id,
+            job: job.clone(),
+            lease_secs: self.lease.as_secs(),
+        };
+        state.leases.insert(
+            id,
+            Leased {
+                job,
+                worker: request.worker,
+                expires: Instant::now() + self.lease,
+            },
+        );
+        response
+    }
+
+    fn heartbeat(&self, request: HeartbeatRequest) -> Response<Body> {
+        let mut state = self.state.lock().unwrap();
+        match state.leases.get_mut(&request.id) {
+            Some(leased) => {
+                leased.expires = Instant::now() + self.lease;
+                status(StatusCode::OK)
+            }
+            None => status(StatusCode::GONE),
+        }
+    }
+
+    fn complete(&self, request: CompleteRequest) -> Response<Body> {
+        let mut state = self.state.lock().unwrap();
+        let Some(leased) = state.leases.remove(&request.id) else {
+            return status(StatusCode::GONE);
+        };
+        if let Some(retry) = request.retry {
+            // At the back of the queue, so that it does not hold up the rest
+            state.queue.insert(0, retry);
+        } else {
+            log::info!(
+                "{} finished {} {}",
+                leased.worker,
+                leased.job.krate.name,
+                leased.job.krate.version
+            );
+        }
+        if state.queue.is_empty() && state.leases.is_empty() {
+            log::info!("All crates are done");
+        }
+        status(StatusCode::OK)
+    }
+
+    fn release(&self, request: ReleaseRequest) -> Response<Body> {
+        let mut state = self.state.lock().unwrap();
+        let Some(leased) = state.leases.remove(&request.id) else {
+            return status(StatusCode::GONE);
+        };
+        log::info!(
+            "{} gave back {} {}",
+            leased.worker,
+            leased.job.krate.name,
+            leased.job.krate.version
+        );
+        state.queue.push(leased.job);
+        status(StatusCode::OK)
+    }
+
+    /// Gives the crates of workers that have gone quiet to the next worker that asks.
+    fn requeue_expired(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let expired = state
+            .leases
+            .iter()
+            .filter(|(_, leased)| leased.expires < now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            let leased = state.leases.remove(&id).unwrap();
+            log::warn!(
+                "{} stopped sending heartbeats for {} {}, requeuing it",
+                leased.worker,
+                leased.job.krate.name,
+                leased.job.krate.version
+            );
+            state.queue.push(leased.job);
+        }
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    Response::new(Body::from(serde_json::to_vec(value).unwrap()))
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn error(code: StatusCode, e: impl std::fmt::Display) -> Response<Body> {
+    let mut response = Response::new(Body::from(e.to_string()));
+    *response.status_mut() = code;
+    response
+}
+
+/// Where the workers of a run get their crates from.
+pub enum Queue {
+    Local {
+        /// In reverse order, so that crates can be popped
+        jobs: Mutex<Vec<Job>>,
+        progress: Progress,
+        /// Whether more crates may be added, so that workers should wait for them
+        open: bool,
+    },
+    Remote(Remote),
+}
+
+/// A crate a worker is running. Dropping it stops the heartbeats.
+pub struct Lease {
+    id: u64,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat.abort();
+        }
+    }
+}
+
+impl Queue {
+    pub fn local(jobs: Vec<Job>, workers: usize) -> Self {
+        Self::new_local(jobs, workers, false)
+    }
+
+    /// A queue that `prioritize` adds crates to, which the workers wait on when it is empty.
+    pub fn open(jobs: Vec<Job>, workers: usize) -> Self {
+        Self::new_local(jobs, workers, true)
+    }
+
+    fn new_local(jobs: Vec<Job>, workers: usize, open: bool) -> Self {
+        let progress = Progress::new(workers);
+        for job in &jobs {
+            progress.queued(job.estimate_secs);
+        }
+        log::info!("{}", progress.eta());
+        Queue::Local {
+            jobs: Mutex::new(jobs),
+            progress,
+            open,
+        }
+    }
+
+    /// Adds crates to run before the ones already in the queue. `jobs` is in reverse order, like
+    /// the queue.
+    pub fn prioritize(&self, new: Vec<Job>) {
+        match self {
+            Queue::Local { jobs, progress, .. } => {
+                for job in &new {
+                    progress.queued(job.estimate_secs);
+                }
+                jobs.lock().unwrap().extend(new);
+            }
+            Queue::Remote(_) => unreachable!("crates are only added to local queues"),
+        }
+    }
+
+    /// The next crate to run, or `None` when there are none left or `stop` says to stop asking.
+    pub async fn next(&self, worker: &str, stop: impl Fn() -> bool) -> Option<(Lease, Job)> {
+        match self {
+            Queue::Local {
+                jobs,
+                progress,
+                open,
+            } => loop {
+                if stop() {
+                    return None;
+                }
+                let (job, backing_off) = {
+                    let mut jobs = jobs.lock().unwrap();
+                    (pop_ready(&mut jobs), !jobs.is_empty())
+                };
+                match job {
+                    Some(job) => {
+                        progress.started(job.estimate_secs);
+                        let lease = Lease {
+                            id: 0,
+                            heartbeat: None,
+                        };
+                        return Some((lease, job));
+                    }
+                    None if *open || backing_off => tokio::time::sleep(POLL_INTERVAL).await,
+                    None => return None,
+                }
+            },
+            Queue::Remote(remote) => loop {
+                if stop() {
+                    return None;
+                }
+                let request = LeaseRequest {
+                    worker: worker.to_string(),
+                };
+                match remote.post("lease", &request).await {
+                    Ok(Some(LeaseResponse::Leased {
+                        id,
+                        job,
+                        lease_secs,
+                    })) => {
+                        let heartbeat = remote.heartbeat(id, Duration::from_secs(lease_secs));
+                        return Some((
+                            Lease {
+                                id,
+                                heartbeat: Some(heartbeat),
+                            },
+                            job,
+                        ));
+                    }
+                    Ok(Some(LeaseResponse::Done)) => return None,
+                    Ok(Some(LeaseResponse::Wait)) => {}
+                    Ok(None) => log::warn!("The coordinator has no crates for {}", worker),
+                    Err(e) => log::warn!("Failed to lease a crate from the coordinator: {}", e),
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            },
+        }
+    }
+
+    /// Puts a crate that was stopped before it finished back, to be the next one run.
+    pub async fn release(&self, lease: Lease, job: Job) {
+        match self {
+            Queue::Local { jobs, progress, .. } => {
+                progress.queued(job.estimate_secs);
+                jobs.lock().unwrap().push(job);
+            }
+            Queue::Remote(remote) => {
+                let request = ReleaseRequest { id: lease.id };
+                remote.report("release", &request).await;
+            }
+        }
+    }
+
+    /// Done with a crate, unless `retry` says to run it again after the rest, or once its
+    /// `not_before` has passed.
+    pub async fn complete(&self, lease: Lease, retry: Option<Job>) {
+        match self {
+            Queue::Local { jobs, progress, .. } => {
+                if let Some(job) = retry {
+                    progress.queued(job.estimate_secs);
+                    jobs.lock().unwrap().insert(0, job);
+                }
+            }
+            Queue::Remote(remote) => {
+                let request = CompleteRequest {
+                    id: lease.id,
+                    retry,
+                };
+                remote.report("complete", &request).await;
+            }
+        }
+    }
+
+    /// How much of the run is left, if this process knows.
+    pub fn eta(&self) -> Option<String> {
+        match self {
+            Queue::Local { progress, .. } => Some(progress.eta()),
+            Queue::Remote(_) => None,
+        }
+    }
+
+    /// The crates that were never handed out, to save for resuming the run.
+    pub fn take_remaining(&self) -> Vec<Job> {
+        match self {
+            Queue::Local { jobs, .. } => std::mem::take(&mut *jobs.lock().unwrap()),
+            Queue::Remote(_) => Vec::new(),
+        }
+    }
+}
+
+/// Talks to a `serve-queue` coordinator.
+pub struct Remote {
+    url: String,
+    http: hyper::Client<hyper::client::HttpConnector>,
+}
+
+impl Remote {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            http: hyper::Client::new(),
+        }
+    }
+
+    /// The response, or `None` if the coordinator had nothing to say.
+    async fn post<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        path: &str,
+        request: &Req,
+    ) -> Result<Option<Resp>> {
+        let request = Request::post(format!("{}/{}", self.url, path))
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(request)?))?;
+        let response = self.http.request(request).await?;
+        let code = response.status();
+        let body = to_bytes(response.into_body()).await?;
+        if code == StatusCode::GONE {
+            return Err(eyre!("the lease has expired"));
+        }
+        if !code.is_success() {
+            return Err(eyre!("{}: {}", code, String::from_utf8_lossy(&body)));
+        }
+        if body.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    /// Sends a request that nothing comes back from. If it doesn't arrive, the lease expires and
+    /// the crate is run again by someone else, so that is only worth a warning.
+    async fn report<Req: Serialize>(&self, path: &str, request: &Req) {
+        if let Err(e) = self.post::<_, serde_json::Value>(path, request).await {
+            log::warn!("Failed to {} a lease: {}", path, e);
+        }
+    }
+
+    /// Keeps a lease from expiring until the task is aborted.
+    fn heartbeat(&self, id: u64, lease: Duration) -> JoinHandle<()> {
+        let remote = Remote {
+            url: self.url.clone(),
+            http: self.http.clone(),
+        };
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(lease / 3).await;
+                if let Err(e) = remote
+                    .post::<_, serde_json::Value>("heartbeat", &HeartbeatRequest { id })
+                    .await
+                {
+                    log::warn!(
+                        "Failed to send a heartbeat for lease {}, its crate may be run twice: {}",
+                        id,
+                        e
+                    );
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Status, Version};
+
+    fn job(name: &str) -> Job {
+        Job {
+            krate: Crate {
+                name: name.to_string(),
+                version: Version::parse("1.0.0"),
+                recent_downloads: None,
+                status: Status::Unknown,
+            },
+            budget: Budget {
+                memory_gb: 2,
+                timeout_secs: 60,
+            },
+            estimate_secs: 1,
+            crashes: 0,
+            not_before: None,
+        }
+    }
+
+    fn coordinator(names: &[&str]) -> Coordinator {
+        Coordinator {
+            lease: Duration::from_secs(60),
+            state: Mutex::new(CoordinatorState {
+                queue: names.iter().rev().map(|name| job(name)).collect(),
+                leases: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    fn lease(coordinator: &Coordinator) -> (u64, String) {
+        let request = LeaseRequest {
+            worker: "worker".to_string(),
+        };
+        match coordinator.lease(request) {
+            LeaseResponse::Leased { id, job, .. } => (id, job.krate.name),
+            LeaseResponse::Wait => panic!("told to wait"),
+            LeaseResponse::Done => panic!("told it is done"),
+        }
+    }
+
+    fn expire(coordinator: &Coordinator, id: u64) {
+        let mut state = coordinator.state.lock().unwrap();
+        state.leases.get_mut(&id).unwrap().expires = Instant::now() - Duration::from_secs(1);
+    }
+
+    #[test]
+    fn expired_leases_are_requeued() {
+        let coordinator = coordinator(&["a", "b"]);
+        let (id, name) = lease(&coordinator);
+        assert_eq!(name, "a");
+        expire(&coordinator, id);
+        coordinator.requeue_expired();
+
+        assert_eq!(lease(&coordinator).1, "a");
+        let heartbeat = coordinator.heartbeat(HeartbeatRequest { id });
+        assert_eq!(heartbeat.status(), StatusCode::GONE);
+        let complete = coordinator.complete(CompleteRequest { id, retry: None });
+        assert_eq!(complete.status(), StatusCode::GONE);
+    }
+
+    #[test]
+    fn heartbeats_renew_leases() {
+        let coordinator = coordinator(&["a", "b"]);
+        let (id, _) = lease(&coordinator);
+        expire(&coordinator, id);
+        let heartbeat = coordinator.heartbeat(HeartbeatRequest { id });
+        assert_eq!(heartbeat.status(), StatusCode::OK);
+        coordinator.requeue_expired();
+
+        assert_eq!(lease(&coordinator).1, "b");
+        assert!(matches!(
+            coordinator.lease(LeaseRequest {
+                worker: "worker".to_string()
+            }),
+            LeaseResponse::Wait
+        ));
+    }
+
+    #[test]
+    fn released_crates_are_next() {
+        let coordinator = coordinator(&["a", "b"]);
+        let (id, _) = lease(&coordinator);
+        let release = coordinator.release(ReleaseRequest { id });
+        assert_eq!(release.status(), StatusCode::OK);
+        assert!(coordinator.state.lock().unwrap().leases.is_empty());
+        assert_eq!(lease(&coordinator).1, "a");
+
+        let again = coordinator.release(ReleaseRequest { id });
+        assert_eq!(again.status(), StatusCode::GONE);
+    }
+
+    #[test]
+    fn completing_twice_is_refused() {
+        let coordinator = coordinator(&["a"]);
+        let (id, _) = lease(&coordinator);
+        let first = coordinator.complete(CompleteRequest { id, retry: None });
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = coordinator.complete(CompleteRequest {
+            id,
+            retry: Some(job("a")),
+        });
+        assert_eq!(second.status(), StatusCode::GONE);
+        // The second one didn't queue its retry
+        assert!(matches!(
+            coordinator.lease(LeaseRequest {
+                worker: "worker".to_string()
+            }),
+            LeaseResponse::Done
+        ));
+    }
+
+    #[test]
+    fn crates_that_are_backing_off_are_skipped() {
+        let backing_off = Job {
+            not_before: Some(unix_now() + 3600),
+            ..job("backing-off")
+        };
+        let mut queue = vec![job("last"), backing_off];
+        assert_eq!(pop_ready(&mut queue).unwrap().krate.name, "last");
+        assert!(pop_ready(&mut queue).is_none());
+        assert_eq!(queue.len(), 1);
+
+        queue[0].not_before = Some(unix_now());
+        assert_eq!(pop_ready(&mut queue).unwrap().krate.name, "backing-off");
+    }
+}
@@ -1,6 +1,150 @@
-use crate::{Crate, Status};
+use crate::{
+    client::Client, deps, diagnose, packages, provenance, repro, rules::rules, Cause, Crate,
+    Status, Tool, Version,
+};
+use clap::Parser;
 use color_eyre::eyre::Result;
-use std::fmt::Write;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(long)]
+    tool: Tool,
+
+    /// A directory of raw logs, laid out as they are in the bucket: `<tool>/raw/<name>/<version>`
+    #[clap(long)]
+    logs: PathBuf,
+
+    /// Where to write the rendered site
+    #[clap(long)]
+    output: PathBuf,
+}
+
+/// Renders a whole site from raw logs on disk, without touching S3.
+///
+/// Every page is written with a `.html` extension so that the output can be browsed from
+/// `file://` or served by any static file server.
+pub fn run(args: Args) -> Result<()> {
+    let crates = find_raw_logs(&args.logs.join(args.tool.raw_path()))?;
+    log::info!("Rendering HTML for {} crates", crates.len());
+
+    let queue = Arc::new(Mutex::new(crates));
+    let rendered = Arc::new(Mutex::new(Vec::new()));
+    std::thread::scope(|s| {
+        let mut threads = Vec::new();
+        for _ in 0..num_cpus::get() {
+            threads.push(s.spawn(|| -> Result<()> {
+                loop {
+                    let Some(mut krate) = queue.lock().unwrap().pop() else {
+                        return Ok(());
+                    };
+                    let raw = fs::read(args.logs.join(args.tool.raw_crate_path(&krate)))?;
+                    let artifacts = Artifacts::read(&args.logs, args.tool, &krate);
+                    let html = render_crate(&krate, args.tool, &raw, &artifacts);
+                    let path = args
+                        .output
+                        .join(format!("{}.html", args.tool.rendered_crate_path(&krate)));
+                    fs::create_dir_all(path.parent().unwrap())?;
+                    fs::write(path, html)?;
+                    if let Some(script) = repro::script(args.tool, &krate, &raw, &artifacts) {
+                        let path = args.output.join(args.tool.repro_crate_path(&krate));
+                        fs::create_dir_all(path.parent().unwrap())?;
+                        fs::write(path, script)?;
+                    }
+                    diagnose(&mut krate, args.tool, &raw)?;
+                    rendered
+                        .lock()
+                        .unwrap()
+                        .push((krate, artifacts.parse_lockfile()));
+                }
+            }));
+        }
+        threads
+            .into_iter()
+            .try_for_each(|thread| thread.join().unwrap())
+    })?;
+    let rendered = Arc::into_inner(rendered).unwrap().into_inner().unwrap();
+    let mut history = deps::History::default();
+    history.record_all(
+        rendered
+            .iter()
+            .map(|(krate, lockfile)| (krate, lockfile.as_ref())),
+    );
+    let mut crates = rendered
+        .into_iter()
+        .map(|(krate, _)| krate)
+        .collect::<Vec<_>>();
+
+    // Without download counts, the best we can do is sort by name.
+    // Then by version, descending, so that dedup leaves only the most recent version of each crate.
+    crates.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| b.version.cmp(&a.version)));
+    crates.dedup_by(|a, b| a.name == b.name);
+
+    let tool_dir = args.output.join(args.tool.to_string());
+    // Only the most recent version of each crate is left
+    let max_versions = crates
+        .iter()
+        .map(|krate| (krate.name.as_str(), krate.version.clone()))
+        .collect::<HashMap<_, _>>();
+    // There are no crate pages to have made it if there were no logs
+    fs::create_dir_all(&tool_dir)?;
+    fs::write(
+        args.output.join(args.tool.landing_page_path()),
+        render_landing_page(&max_versions, ".html")?,
+    )?;
+    fs::write(
+        tool_dir.join("ub.html"),
+        render_ub(&crates, &history, ".html")?,
+    )?;
+    fs::write(
+        tool_dir.join("unsupported.html"),
+        render_unsupported(&crates, ".html")?,
+    )?;
+    fs::write(
+        tool_dir.join("clusters.html"),
+        render_clusters(&crates, ".html")?,
+    )?;
+    fs::write(
+        tool_dir.join("build-failures.html"),
+        render_build_failures(&crates, ".html")?,
+    )?;
+    fs::write(tool_dir.join("403.html"), ERROR_PAGE)?;
+
+    log::info!(
+        "Wrote the site to {}",
+        args.output.join(args.tool.landing_page_path()).display()
+    );
+    Ok(())
+}
+
+fn find_raw_logs(raw_dir: &Path) -> Result<Vec<Crate>> {
+    let mut crates = Vec::new();
+    for name in fs::read_dir(raw_dir)? {
+        let name = name?;
+        if !name.file_type()?.is_dir() {
+            continue;
+        }
+        for version in fs::read_dir(name.path())? {
+            let version = version?;
+            if !version.file_type()?.is_file() {
+                continue;
+            }
+            crates.push(Crate {
+                name: name.file_name().to_string_lossy().into_owned(),
+                version: Version::parse(&version.file_name().to_string_lossy()),
+                recent_downloads: None,
+                status: Status::Unknown,
+            });
+        }
+    }
+    Ok(crates)
+}
 
 #[rustfmt::skip]
 macro_rules! log_format {
@@ -32,11 +176,92 @@ function scroll_to_ub() {{
 </script>
 <body onload="scroll_to_ub()">
 <pre style="text-align: center;">{} {}</pre>
-<pre>{}</pre></body></html>"#
+{}<pre>{}</pre></body></html>"#
+    }
+}
+
+/// What the worker reported about a run besides its log.
+#[derive(Default)]
+pub struct Artifacts {
+    /// The flags `get-args` passed to cargo
+    pub args: Option<String>,
+    /// The toolchain versions and the environment variables that configure the tool, as `export`s
+    pub env: Option<String>,
+    /// The `Cargo.lock` the dependencies resolved to
+    pub lockfile: Option<String>,
+}
+
+impl Artifacts {
+    /// Reads whichever artifacts exist from a directory laid out like the bucket.
+    pub fn read(dir: &Path, tool: Tool, krate: &Crate) -> Self {
+        Self {
+            args: fs::read_to_string(dir.join(tool.args_crate_path(krate))).ok(),
+            env: fs::read_to_string(dir.join(tool.env_crate_path(krate))).ok(),
+            lockfile: fs::read_to_string(dir.join(tool.lockfile_crate_path(krate))).ok(),
+        }
+    }
+
+    pub async fn download(client: &Client, krate: &Crate) -> Result<Self> {
+        let text = |bytes: Option<Vec<u8>>| bytes.map(|b| String::from_utf8_lossy(&b).into_owned());
+        Ok(Self {
+            args: text(client.download_args(krate).await?),
+            env: text(client.download_env(krate).await?),
+            lockfile: text(client.download_lockfile(krate).await?),
+        })
+    }
+
+    pub fn parse_lockfile(&self) -> Option<deps::Lockfile> {
+        self.lockfile.as_deref().map(deps::Lockfile::parse)
     }
 }
 
-pub fn render_crate(krate: &Crate, output: &[u8]) -> String {
+/// A collapsed block above the log with the flags and direct dependency versions the result
+/// depends on, and a link to a script that reproduces it.
+fn render_artifacts(krate: &Crate, artifacts: &Artifacts, reproducible: bool) -> String {
+    if artifacts.args.is_none() && artifacts.lockfile.is_none() {
+        return String::new();
+    }
+    let mut output = String::from("<details><summary>Flags and dependencies</summary><pre>");
+    if reproducible {
+        // Logs are at <tool>/logs/<name>/<version>, scripts at <tool>/repro/<name>/<version>.sh
+        writeln!(
+            output,
+            "<a href=\"../../repro/{}/{}.sh\">Script to reproduce this</a>",
+            krate.name, krate.version
+        )
+        .unwrap();
+    }
+    if let Some(args) = &artifacts.args {
+        writeln!(output, "cargo flags: {}", escape(args.trim())).unwrap();
+    }
+    if let Some(lockfile) = artifacts.parse_lockfile() {
+        for package in lockfile.direct_dependencies(&krate.name) {
+            writeln!(output, "{} {}", escape(&package.name), package.version).unwrap();
+        }
+    }
+    output.push_str("</pre></details>\n");
+    output
+}
+
+/// A collapsed block above the log with what produced it.
+fn render_provenance(provenance: Option<&str>) -> String {
+    match provenance {
+        Some(provenance) => format!(
+            "<details><summary>Provenance</summary><pre>{}</pre></details>\n",
+            escape(provenance)
+        ),
+        None => String::new(),
+    }
+}
+
+pub fn render_crate(krate: &Crate, tool: Tool, output: &[u8], artifacts: &Artifacts) -> String {
+    let (provenance, output) = provenance::split(output);
+    // The same conditions as `repro::script` producing a script
+    let reproducible = artifacts.env.is_some()
+        && provenance
+            .as_deref()
+            .and_then(provenance::recorded_toolchain)
+            .is_some();
     let (css, mut encoded) =
         ansi_to_html::render(format!("{}/{}", krate.name, krate.version), output);
 
@@ -46,14 +271,7 @@ pub fn render_crate(krate: &Crate, output: &[u8]) -> String {
         encoded.remove(encoded.len() - ending.len());
     }
 
-    for pat in [
-        "Undefined Behavior:",
-        "ERROR: AddressSanitizer:",
-        "SIGILL: illegal instruction",
-        "attempted to leave type",
-        "misaligned pointer dereference",
-        "unsafe precondition(s) violated",
-    ] {
+    for pat in rules().ub_markers(tool) {
         if encoded.contains(pat) {
             let replacement = format!("<span id=\"ub\"></span>{}", pat);
             encoded = encoded.replacen(pat, &replacement, 1);
@@ -63,7 +281,14 @@ pub fn render_crate(krate: &Crate, output: &[u8]) -> String {
 
     format!(
         log_format!(),
-        css, krate.name, krate.version, krate.name, krate.version, encoded
+        css,
+        krate.name,
+        krate.version,
+        krate.name,
+        krate.version,
+        render_provenance(provenance.as_deref())
+            + &render_artifacts(krate, artifacts, reproducible),
+        encoded
     )
 }
 
@@ -146,7 +371,7 @@ let build_log;
 function change_log(crate, version) {
     let path = window.location.pathname;
     let base = path.slice(0, path.lastIndexOf('/'));
-    let html = "<object data=\"" + base + "/logs/" + crate + "/" + encodeURIComponent(version) + "\" width=100% height=100%></object>";
+    let html = "<object data=\"" + base + "/logs/" + crate + "/" + encodeURIComponent(version) + log_suffix + "\" width=100% height=100%></object>";
     if (build_log == undefined)  {
         build_log = document.getElementById("log");
     }
@@ -197,16 +422,34 @@ Click on a crate to the right to display its build log
 <div class="crates" onclick=crate_click()>
 "#;
 
-pub fn render_ub(crates: &[Crate]) -> Result<String> {
+/// `log_suffix` is appended to the path of each crate's log, so that pages which are browsed from
+/// disk can link to files that have an extension.
+pub fn render_ub(crates: &[Crate], history: &deps::History, log_suffix: &str) -> Result<String> {
     let mut output = String::new();
     writeln!(output, "{}", OUTPUT_HEADER)?;
+    writeln!(
+        output,
+        "<script>const log_suffix = \"{}\";</script>",
+        log_suffix
+    )?;
     for c in crates {
         if let Status::UB { cause: causes, .. } = &c.status {
             write!(output, "<div class=\"row\">{} {}<br>", c.name, c.version,)?;
             for cause in causes {
                 write!(output, "{}", cause.kind)?;
-                if let Some(source_crate) = &cause.source_crate {
-                    write!(output, " ({source_crate})")?;
+                if let Some(source) = cause.source() {
+                    write!(output, " ({source}")?;
+                    if let (Some(name), Some(version)) =
+                        (&cause.source_crate, &cause.source_version)
+                    {
+                        if let Some(fixed) = history.fixed_in(name, version) {
+                            write!(
+                                output,
+                                "; fixed by upgrading {name} from {version} to &ge;{fixed}"
+                            )?;
+                        }
+                    }
+                    write!(output, ")")?;
                 }
                 write!(output, ", ")?;
             }
@@ -219,8 +462,275 @@ pub fn render_ub(crates: &[Crate]) -> Result<String> {
     Ok(output)
 }
 
-pub const LANDING_PAGE: &str = r#"<!DOCTYPE HTML>
+const TABLE_HEADER: &str = r#"<!DOCTYPE HTML>
 <html><head><style>
+body {
+    background: #111;
+    color: #eee;
+    font-family: sans-serif;
+    font-size: 16px;
+}
+a {
+    color: #eee;
+}
+table {
+    border-collapse: collapse;
+}
+th, td {
+    text-align: left;
+    vertical-align: top;
+    border-bottom: 1px solid #333;
+    padding: 0.3em 0.8em;
+}
+.mono {
+    font-family: monospace;
+}
+</style>"#;
+
+/// Ranks the operations Miri does not support by how many crates they block.
+pub fn render_unsupported(crates: &[Crate], log_suffix: &str) -> Result<String> {
+    #[derive(Default)]
+    struct Row<'a> {
+        crates: Vec<&'a Crate>,
+        tests: usize,
+        downloads: u64,
+    }
+    let mut rows: HashMap<(&str, Option<&str>), Row> = HashMap::new();
+    for c in crates {
+        if let Status::Unsupported { ops } = &c.status {
+            for op in ops {
+                let row = rows
+                    .entry((op.kind.as_str(), op.symbol.as_deref()))
+                    .or_default();
+                row.crates.push(c);
+                row.tests += op.tests;
+                row.downloads += c.recent_downloads.unwrap_or(0);
+            }
+        }
+    }
+    let mut rows = rows.into_iter().collect::<Vec<_>>();
+    rows.sort_by(|(a_key, a), (b_key, b)| {
+        b.crates
+            .len()
+            .cmp(&a.crates.len())
+            .then_with(|| b.downloads.cmp(&a.downloads))
+            .then_with(|| a_key.cmp(b_key))
+    });
+
+    let mut output = String::from(TABLE_HEADER);
+    writeln!(output, "<title>Unsupported operations</title></head><body>")?;
+    writeln!(
+        output,
+        "<p>Operations that Miri does not support, by how many crates' tests they stop.</p>"
+    )?;
+    writeln!(
+        output,
+        "<table><tr><th>Kind</th><th>Symbol</th><th>Crates</th><th>Tests</th><th>Downloads</th><th>For example</th></tr>"
+    )?;
+    for ((kind, symbol), row) in rows {
+        write!(
+            output,
+            "<tr><td>{}</td><td class=\"mono\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>",
+            escape(kind),
+            escape(symbol.unwrap_or_default()),
+            row.crates.len(),
+            row.tests,
+            row.downloads
+        )?;
+        for c in row.crates.iter().take(5) {
+            write!(
+                output,
+                "<a href=\"logs/{}/{}{}\">{} {}</a> ",
+                c.name, c.version, log_suffix, c.name, c.version
+            )?;
+        }
+        writeln!(output, "</td></tr>")?;
+    }
+    writeln!(output, "</table></body></html>")?;
+    Ok(output)
+}
+
+/// Groups UB findings that are the same bug in a shared dependency, so that one bug in a popular
+/// crate is one entry instead of hundreds.
+pub fn render_clusters(crates: &[Crate], log_suffix: &str) -> Result<String> {
+    let mut clusters: HashMap<String, (&Cause, Vec<&Crate>)> = HashMap::new();
+    for c in crates {
+        if let Status::UB { cause: causes } = &c.status {
+            for cause in causes {
+                if let Some(fingerprint) = cause.fingerprint() {
+                    let (_, affected) = clusters.entry(fingerprint).or_insert((cause, Vec::new()));
+                    if !affected.iter().any(|a| a.name == c.name) {
+                        affected.push(c);
+                    }
+                }
+            }
+        }
+    }
+    let mut clusters = clusters
+        .into_values()
+        .map(|(cause, mut affected)| {
+            affected.sort_by_key(|c| std::cmp::Reverse(c.recent_downloads));
+            let downloads: u64 = affected
+                .iter()
+                .map(|c| c.recent_downloads.unwrap_or(0))
+                .sum();
+            (cause, affected, downloads)
+        })
+        .collect::<Vec<_>>();
+    clusters.sort_by(|a, b| {
+        b.1.len()
+            .cmp(&a.1.len())
+            .then_with(|| b.2.cmp(&a.2))
+            .then_with(|| a.0.cmp(b.0))
+    });
+
+    let mut output = String::from(TABLE_HEADER);
+    writeln!(output, "<title>UB by root cause</title></head><body>")?;
+    writeln!(
+        output,
+        "<p>UB that was found in a dependency, grouped by where it happened.</p>"
+    )?;
+    writeln!(
+        output,
+        "<table><tr><th>Kind</th><th>In</th><th>Where</th><th>Crates</th><th>Downloads</th><th>Example</th><th>Affected</th></tr>"
+    )?;
+    for (cause, affected, downloads) in clusters {
+        let example = affected[0];
+        write!(
+            output,
+            "<tr><td>{}</td><td>{}</td><td class=\"mono\">{}</td><td>{}</td><td>{}</td><td><a href=\"logs/{}/{}{}\">{} {}</a></td><td>",
+            escape(&cause.kind),
+            escape(&cause.source().unwrap_or_default()),
+            escape(cause.frame.as_deref().unwrap_or_default()),
+            affected.len(),
+            downloads,
+            example.name,
+            example.version,
+            log_suffix,
+            example.name,
+            example.version,
+        )?;
+        let names = affected.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+        write!(output, "{}", escape(&names.join(", ")))?;
+        writeln!(output, "</td></tr>")?;
+    }
+    writeln!(output, "</table></body></html>")?;
+    Ok(output)
+}
+
+/// Summarizes why crates fail to build, and which packages would fix the missing native
+/// libraries.
+pub fn render_build_failures(crates: &[Crate], log_suffix: &str) -> Result<String> {
+    let mut by_kind: HashMap<&str, Vec<&Crate>> = HashMap::new();
+    let mut by_detail: HashMap<(&str, &str), Vec<&Crate>> = HashMap::new();
+    for c in crates {
+        if let Status::BuildFailure { kind, detail } = &c.status {
+            by_kind.entry(kind).or_default().push(c);
+            if let Some(detail) = detail {
+                by_detail.entry((kind, detail)).or_default().push(c);
+            }
+        }
+    }
+    let mut by_kind = by_kind.into_iter().collect::<Vec<_>>();
+    by_kind.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(b.0)));
+    let mut by_detail = by_detail.into_iter().collect::<Vec<_>>();
+    by_detail.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(&b.0)));
+
+    let downloads =
+        |crates: &[&Crate]| -> u64 { crates.iter().map(|c| c.recent_downloads.unwrap_or(0)).sum() };
+
+    let mut output = String::from(TABLE_HEADER);
+    writeln!(output, "<title>Build failures</title></head><body>")?;
+    writeln!(output, "<h3>Why crates fail to build</h3>")?;
+    writeln!(
+        output,
+        "<table><tr><th>Reason</th><th>Crates</th><th>Downloads</th></tr>"
+    )?;
+    for (kind, crates) in &by_kind {
+        writeln!(
+            output,
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(kind),
+            crates.len(),
+            downloads(crates)
+        )?;
+    }
+    writeln!(output, "</table>")?;
+
+    writeln!(output, "<h3>What they are missing</h3>")?;
+    writeln!(
+        output,
+        "<table><tr><th>Reason</th><th>Missing</th><th>Crates</th><th>Downloads</th><th>Package to add to docker/packages.txt</th><th>For example</th></tr>"
+    )?;
+    for ((kind, detail), crates) in &by_detail {
+        let package = match packages::suggest(detail) {
+            _ if *kind == "removed nightly feature" => String::new(),
+            Some(package) if packages::is_installed(&package) => {
+                format!("{} (already installed)", escape(&package))
+            }
+            Some(package) => escape(&package),
+            None => String::from("?"),
+        };
+        write!(
+            output,
+            "<tr><td>{}</td><td class=\"mono\">{}</td><td>{}</td><td>{}</td><td class=\"mono\">{}</td><td>",
+            escape(kind),
+            escape(detail),
+            crates.len(),
+            downloads(crates),
+            package,
+        )?;
+        for c in crates.iter().take(5) {
+            write!(
+                output,
+                "<a href=\"logs/{}/{}{}\">{} {}</a> ",
+                c.name, c.version, log_suffix, c.name, c.version
+            )?;
+        }
+        writeln!(output, "</td></tr>")?;
+    }
+    writeln!(output, "</table></body></html>")?;
+    Ok(output)
+}
+
+/// For text in HTML or XML, including attribute values.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render_landing_page(
+    max_versions: &HashMap<&str, Version>,
+    log_suffix: &str,
+) -> Result<String> {
+    let mut output = String::from(LANDING_PAGE);
+    // Next to this page, with the same suffix as the logs
+    let pages = [
+        ("ub", "every crate with UB"),
+        ("clusters", "UB that many crates share"),
+        ("unsupported", "what the tool does not support"),
+        ("build-failures", "why crates fail to build"),
+    ]
+    .map(|(page, text)| format!("<a href=\"{page}{log_suffix}\">{text}</a>"));
+    writeln!(output, "<p>Or browse {}.", pages.join(", "))?;
+    writeln!(output, "<script>")?;
+    writeln!(output, "const log_suffix = \"{}\";", log_suffix)?;
+    writeln!(output, "const all =\n{{")?;
+    for (name, version) in max_versions {
+        writeln!(output, "\"{}\":\"{}\",", name, version)?;
+    }
+    output.pop(); // remove the trailing newline
+    output.pop(); // remove the last comma
+    output.push_str("};</script></html>");
+    Ok(output)
+}
+
+const LANDING_PAGE: &str = r#"<!DOCTYPE HTML>
+<html><head>
+<link rel="alternate" type="application/atom+xml" title="New UB" href="feed.xml">
+<style>
 body {
     background: #111;
     color: #eee;
@@ -228,6 +738,9 @@ body {
     font-size: 20px;
     visibility: hidden;
 }
+a {
+    color: #eee;
+}
 input {
     background: #111;
     color: #eee;
@@ -262,7 +775,7 @@ function init() {
 function move_to(crate, version) {
     let url = window.location.href;
     let base = url.slice(0, url.lastIndexOf('/'));
-    window.location.href = base + "/logs/" + crate + "/" + encodeURIComponent(version)
+    window.location.href = base + "/logs/" + crate + "/" + encodeURIComponent(version) + log_suffix;
 }
 function decode_params() {
     var params = {};
@@ -281,6 +794,53 @@ function decode_params() {
 <input id="search" style="width: 80%; height: 100%; margin: 0 auto;"></input>
 <p><span id=search-result style="font-family:monospace; font-size: 19px;"></span>
 </div>
-<script>
-const all =
-{"#;
+"#;
+
+pub static ERROR_PAGE: &str = r#"<!DOCTYPE HTML>
+<html><head><style>
+body {
+    background: #111;
+    color: #eee;
+}
+pre {
+    word-wrap: break-word;
+    white-space: pre-wrap;
+    font-size: 14px;
+    font-size-adjust: none;
+    text-size-adjust: none;
+    -webkit-text-size-adjust: 100%;
+    -moz-text-size-adjust: 100%;
+    -ms-text-size-adjust: 100%;
+}
+</style><title>oops</title></head>
+<body><pre><span style='color:#f55; font-weight:bold'>error</span>: No such file or directory (http error 404)
+
+<span style='color:#f55; font-weight:bold'>error</span>: aborting due to previous error</pre></body></html>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_markup() {
+        assert_eq!(
+            escape(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn landing_page_links_the_other_pages() {
+        let versions = HashMap::from([("tree", Version::parse("1.2.0"))]);
+        let rendered = render_landing_page(&versions, ".html").unwrap();
+        for page in ["ub", "clusters", "unsupported", "build-failures"] {
+            assert!(
+                rendered.contains(&format!("href=\"{page}.html\"")),
+                "{page}"
+            );
+        }
+        let synced = render_landing_page(&versions, "").unwrap();
+        assert!(synced.contains("href=\"ub\""));
+    }
+}
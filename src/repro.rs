@@ -0,0 +1,229 @@
+//! Shell scripts that reproduce a result outside of our sandbox, for crate authors.
+
+use crate::{
+    client::Client, provenance, render::Artifacts, sandbox::IMAGE, Crate, Status, Tool, Version,
+};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Result};
+use std::{fmt::Write, fs, path::PathBuf};
+
+static NEXTEST_CONFIG: &str = include_str!("../docker/nextest.toml");
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(long)]
+    tool: Tool,
+
+    /// Read the result from this bucket
+    #[clap(long, conflicts_with = "logs", required_unless_present = "logs")]
+    bucket: Option<String>,
+
+    /// Read the result from a directory laid out like the bucket
+    #[clap(long)]
+    logs: Option<PathBuf>,
+
+    /// The crate to reproduce, as `name@version`
+    krate: String,
+}
+
+/// Prints the script which reproduces one result.
+#[tokio::main]
+pub async fn run(args: Args) -> Result<()> {
+    let (name, version) = args
+        .krate
+        .split_once('@')
+        .ok_or_else(|| eyre!("Expected name@version, got {}", args.krate))?;
+    let krate = Crate {
+        name: name.to_string(),
+        version: Version::parse(version),
+        recent_downloads: None,
+        status: Status::Unknown,
+    };
+    let (raw, artifacts) = match (&args.bucket, &args.logs) {
+        (Some(bucket), _) => {
+            let client = Client::new(args.tool, bucket).await?;
+            (
+                client.download_raw(&krate).await?,
+                Artifacts::download(&client, &krate).await?,
+            )
+        }
+        (None, Some(logs)) => (
+            fs::read(logs.join(args.tool.raw_crate_path(&krate)))?,
+            Artifacts::read(logs, args.tool, &krate),
+        ),
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+    let script = script(args.tool, &krate, &raw, &artifacts).ok_or_else(|| {
+        eyre!(
+            "The environment of {} {} was not saved, so it cannot be reproduced",
+            krate.name,
+            krate.version
+        )
+    })?;
+    print!("{}", script);
+    Ok(())
+}
+
+/// A bash script that sets up the same toolchain, flags, and dependency versions as the run that
+/// produced this result, then runs the same commands as `docker/run.sh`. Results from before the
+/// environment and the toolchain were saved can't be reproduced, so they have no script.
+pub fn script(tool: Tool, krate: &Crate, output: &[u8], artifacts: &Artifacts) -> Option<String> {
+    let env = artifacts.env.as_ref()?;
+    let (provenance, _) = provenance::split(output);
+    let toolchain = provenance::recorded_toolchain(provenance.as_deref()?)?;
+    let dir = format!("{}-{}", krate.name, krate.version);
+    let mut s = String::new();
+    writeln!(s, "#!/usr/bin/env bash").unwrap();
+    writeln!(
+        s,
+        "# Reproduces the {} result for {} {} from crater-at-home.",
+        tool, krate.name, krate.version
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "# Crates often need native libraries, which are all in the image the result came from. Build"
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "# it from docker/ in crater-at-home, save this as repro.sh, and run:"
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "#   docker run --rm -it --entrypoint=bash -v \"$PWD:/repro\" {IMAGE} /repro/repro.sh"
+    )
+    .unwrap();
+    writeln!(s, "set -eu -o pipefail").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "{}", env.trim_end()).unwrap();
+    writeln!(s, "export TOOLCHAIN={}", toolchain).unwrap();
+    writeln!(
+        s,
+        "ARGS={}",
+        shell_quote(artifacts.args.as_deref().unwrap_or_default().trim())
+    )
+    .unwrap();
+    writeln!(s).unwrap();
+
+    s.push_str(
+        r#"if ! command -v rustup > /dev/null; then
+    curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y --profile=minimal --default-toolchain=none
+    export PATH=$HOME/.cargo/bin:$PATH
+fi
+rustup toolchain install $TOOLCHAIN --profile=minimal --component=rust-src --target=$TARGET
+"#,
+    );
+    match tool {
+        Tool::Miri => s.push_str(
+            "rustup component add --toolchain $TOOLCHAIN miri\n\
+             cargo install --locked cargo-nextest\n",
+        ),
+        Tool::Asan => s.push_str(
+            "cargo install --git https://github.com/RalfJung/cargo-careful cargo-careful\n",
+        ),
+        Tool::Build | Tool::Check => {}
+    }
+    writeln!(s).unwrap();
+
+    writeln!(s, "mkdir {dir} && cd {dir}").unwrap();
+    writeln!(
+        s,
+        "curl -sSfL https://static.crates.io/crates/{name}/{name}-{version}.crate | tar xzf - --strip-components=1",
+        name = krate.name,
+        version = krate.version
+    )
+    .unwrap();
+    if let Some(lockfile) = &artifacts.lockfile {
+        writeln!(s, "# The dependency versions the result was produced with").unwrap();
+        writeln!(s, "cat > Cargo.lock <<'CRATER_AT_HOME_EOF'").unwrap();
+        writeln!(s, "{}", lockfile.trim_end()).unwrap();
+        writeln!(s, "CRATER_AT_HOME_EOF").unwrap();
+    }
+    writeln!(s).unwrap();
+
+    // These are the commands from docker/run.sh, without the timeout and terminal emulation
+    writeln!(
+        s,
+        "# A failure is what this reproduces, so don't stop at the first one"
+    )
+    .unwrap();
+    writeln!(s, "set +e").unwrap();
+    let cargo = "cargo +$TOOLCHAIN";
+    match tool {
+        Tool::Build => writeln!(s, "{cargo} test --no-run $ARGS --target=$TARGET").unwrap(),
+        Tool::Check => writeln!(s, "{cargo} check $ARGS --target=$TARGET").unwrap(),
+        Tool::Asan => {
+            writeln!(s, "ulimit -s 1048576").unwrap();
+            writeln!(
+                s,
+                "{cargo} careful test -Zcareful-sanitizer=address --no-fail-fast $ARGS --target=$TARGET"
+            )
+            .unwrap();
+        }
+        Tool::Miri => {
+            writeln!(s, "cat > nextest.toml <<'CRATER_AT_HOME_EOF'").unwrap();
+            write!(s, "{}", NEXTEST_CONFIG).unwrap();
+            writeln!(s, "CRATER_AT_HOME_EOF").unwrap();
+            writeln!(
+                s,
+                "{cargo} miri nextest run --no-fail-fast --config-file=nextest.toml $ARGS --target=$TARGET"
+            )
+            .unwrap();
+            writeln!(
+                s,
+                "{cargo} miri test --doc --no-fail-fast $ARGS --target=$TARGET"
+            )
+            .unwrap();
+        }
+    }
+    Some(s)
+}
+
+/// Quotes a string so that bash reads it as a single word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn krate() -> Crate {
+        Crate {
+            name: String::from("arena"),
+            version: Version::parse("0.3.1"),
+            recent_downloads: None,
+            status: Status::Unknown,
+        }
+    }
+
+    fn artifacts(env: &str) -> Artifacts {
+        Artifacts {
+            args: Some(String::from("--all-features")),
+            env: Some(env.to_string()),
+            lockfile: None,
+        }
+    }
+
+    #[test]
+    fn toolchain_from_provenance() {
+        let log = b"=== crater-at-home provenance ===\n\
+            toolchain: nightly-2026-10-18\n\
+            === end of provenance ===\n\
+            test result: ok\n";
+        let env = "declare -x TARGET=\"x86_64-unknown-linux-gnu\"";
+        let script = script(Tool::Miri, &krate(), log, &artifacts(env)).unwrap();
+        assert!(script.contains("set -eu -o pipefail\n"));
+        assert!(script.contains("export TOOLCHAIN=nightly-2026-10-18\n"));
+        assert!(script.contains(&format!(" {IMAGE} ")));
+    }
+
+    #[test]
+    fn no_script_without_a_toolchain() {
+        // There is nothing to install
+        let env = "declare -x TARGET=\"x86_64-unknown-linux-gnu\"";
+        assert_eq!(script(Tool::Asan, &krate(), b"", &artifacts(env)), None);
+    }
+}
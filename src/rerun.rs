@@ -0,0 +1,261 @@
+//! Which crates that already have a recent result `run` should run again anyway.
+
+use crate::{
+    client::Client, diagnose::diagnose_status, provenance, rules::rules, sandbox::Sandbox, Crate,
+    Status, Version,
+};
+use color_eyre::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+// The policies are combined: a crate is rerun if any of them picks it.
+#[derive(clap::Args, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Policies {
+    /// Rerun every crate
+    #[clap(long)]
+    pub rerun: bool,
+
+    /// Rerun crates whose result is older than this
+    #[clap(long, default_value_t = 30)]
+    pub max_age_days: i64,
+
+    /// Rerun crates whose last diagnosis is one of these
+    #[clap(long, value_enum, value_delimiter = ',')]
+    rerun_status: Vec<StatusClass>,
+
+    /// Rerun crates whose result came from a different nightly than the one we would run them
+    /// with now, or from before the nightly was recorded
+    #[clap(long)]
+    rerun_toolchain_changed: bool,
+
+    /// Rerun crates whose last diagnosis is not what the current rules make of their log
+    #[clap(long)]
+    rerun_mismatched: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum StatusClass {
+    Passing,
+    /// Any error, including timeouts and running out of memory
+    Error,
+    /// Timed out inside the container, or hung and killed by the host
+    Timeout,
+    Oom,
+    Ub,
+    Unsupported,
+    BuildFailure,
+}
+
+impl StatusClass {
+    fn matches(self, status: &Status) -> bool {
+        match (self, status) {
+            (StatusClass::Passing, Status::Passing) => true,
+            (StatusClass::Error, Status::Error(_)) => true,
+            // These are the kinds rules.json uses
+            (StatusClass::Timeout, Status::Error(kind)) => kind == "Timeout" || kind == "Hung",
+            (StatusClass::Oom, Status::Error(kind)) => kind == "OOM",
+            (StatusClass::Ub, Status::UB { .. }) => true,
+            (StatusClass::Unsupported, Status::Unsupported { .. }) => true,
+            (StatusClass::BuildFailure, Status::BuildFailure { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+type Key = (String, Version);
+
+/// How much of each log to download for its provenance block, which is a few KB even with the
+/// whole environment in it.
+const PROVENANCE_BYTES: usize = 64 * 1024;
+
+impl Policies {
+    /// Whether `select` asks the image which nightly it runs, so the image has to be built first.
+    pub fn needs_image(&self) -> bool {
+        self.rerun_toolchain_changed
+    }
+
+    /// Which of the crates with a result younger than `max_age_days` to run again.
+    pub async fn select(
+        &self,
+        client: &Arc<Client>,
+        fresh: &[Crate],
+        sandbox: &Sandbox,
+    ) -> Result<HashSet<Key>> {
+        let mut selected = HashSet::new();
+        if !self.rerun_status.is_empty() || self.rerun_mismatched {
+            let diagnoses = match client
+                .download_if_exists(&client.tool().diagnoses_path())
+                .await?
+            {
+                Some(json) => serde_json::from_slice::<Vec<Crate>>(&json)?,
+                None => {
+                    log::warn!("There are no diagnoses to rerun crates by, run `sync` first");
+                    Vec::new()
+                }
+            };
+            let diagnoses = diagnoses
+                .into_iter()
+                .map(|c| ((c.name, c.version), c.status))
+                .collect::<HashMap<_, _>>();
+            selected.extend(self.by_status(fresh, &diagnoses));
+            if self.rerun_mismatched {
+                let mismatched = for_each_result(client, fresh, |client, krate| async move {
+                    let raw = client.download_raw(&krate).await?;
+                    Ok((krate, diagnose_status(rules(), client.tool(), &raw)))
+                })
+                .await?
+                .into_iter()
+                .filter(|(krate, status)| {
+                    let key = (krate.name.clone(), krate.version.clone());
+                    // Status can't be compared, but what we store of it can
+                    diagnoses.get(&key).is_some_and(|previous| {
+                        serde_json::to_value(previous).ok() != serde_json::to_value(status).ok()
+                    })
+                })
+                .map(|(krate, _)| (krate.name, krate.version))
+                .collect::<Vec<_>>();
+                log::info!("{} crates are diagnosed differently now", mismatched.len());
+                selected.extend(mismatched);
+            }
+        }
+        if self.rerun_toolchain_changed {
+            let current = provenance::toolchain(sandbox)?;
+            let changed = for_each_result(client, fresh, |client, krate| async move {
+                let start = client.download_raw_start(&krate, PROVENANCE_BYTES).await?;
+                // A block that is cut off has no toolchain, so the crate is rerun
+                let (header, _) = provenance::split(&start);
+                let toolchain = header
+                    .as_deref()
+                    .and_then(provenance::recorded_toolchain)
+                    .map(String::from);
+                Ok((krate, toolchain))
+            })
+            .await?
+            .into_iter()
+            .filter(|(_, toolchain)| toolchain.as_deref() != Some(current))
+            .map(|(krate, _)| (krate.name, krate.version))
+            .collect::<Vec<_>>();
+            log::info!(
+                "{} crates were run with a nightly other than {}",
+                changed.len(),
+                current
+            );
+            selected.extend(changed);
+        }
+        Ok(selected)
+    }
+
+    /// The crates whose last diagnosis is in one of the `--rerun-status` classes.
+    fn by_status(&self, fresh: &[Crate], diagnoses: &HashMap<Key, Status>) -> Vec<Key> {
+        fresh
+            .iter()
+            .map(|krate| (krate.name.clone(), krate.version.clone()))
+            .filter(|key| {
+                diagnoses.get(key).is_some_and(|status| {
+                    self.rerun_status.iter().any(|class| class.matches(status))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Looks at the stored result of each crate, a bounded number at a time.
+async fn for_each_result<T, F, Fut>(client: &Arc<Client>, crates: &[Crate], f: F) -> Result<Vec<T>>
+where
+    T: Send + 'static,
+    F: Fn(Arc<Client>, Crate) -> Fut,
+    Fut: std::future::Future<Output = Result<T>> + Send + 'static,
+{
+    let limit = Arc::new(Semaphore::new(256));
+    let mut tasks = JoinSet::new();
+    for krate in crates {
+        let permit = limit.clone().acquire_owned().await.unwrap();
+        let fut = f(client.clone(), krate.clone());
+        tasks.spawn(async move {
+            let result = fut.await;
+            drop(permit);
+            result
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(task) = tasks.join_next().await {
+        results.push(task??);
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Tool;
+
+    fn error(kind: &str) -> Status {
+        Status::Error(kind.to_string())
+    }
+
+    /// What the rules make of a log that ends with `line`.
+    fn diagnosed(line: &str) -> Status {
+        let log = format!("Running tests\n{line}\n");
+        diagnose_status(rules(), Tool::Miri, log.as_bytes())
+    }
+
+    #[test]
+    fn classes_match_the_kinds_the_rules_use() {
+        let timeout = diagnosed("Command exited with non-zero status 124");
+        let hung = diagnosed("crater-at-home: killed after exceeding the time limit");
+        let oom = diagnosed("Command exited with non-zero status 255");
+        assert!(StatusClass::Timeout.matches(&timeout));
+        assert!(StatusClass::Timeout.matches(&hung));
+        assert!(!StatusClass::Timeout.matches(&oom));
+        assert!(StatusClass::Oom.matches(&oom));
+        assert!(!StatusClass::Oom.matches(&timeout));
+        for status in [&timeout, &hung, &oom] {
+            assert!(StatusClass::Error.matches(status));
+            assert!(!StatusClass::Passing.matches(status));
+        }
+    }
+
+    #[test]
+    fn classes_match_their_statuses() {
+        let build_failure = Status::BuildFailure {
+            kind: "missing library".to_string(),
+            detail: None,
+        };
+        assert!(StatusClass::BuildFailure.matches(&build_failure));
+        assert!(!StatusClass::Error.matches(&build_failure));
+        assert!(StatusClass::Passing.matches(&Status::Passing));
+        assert!(StatusClass::Ub.matches(&Status::UB { cause: Vec::new() }));
+        assert!(StatusClass::Unsupported.matches(&Status::Unsupported { ops: Vec::new() }));
+        assert!(!StatusClass::Ub.matches(&Status::Unknown));
+    }
+
+    #[test]
+    fn select_by_status() {
+        let krate = |name: &str| Crate {
+            name: name.to_string(),
+            version: Version::parse("1.0.0"),
+            recent_downloads: None,
+            status: Status::Unknown,
+        };
+        let key = |name: &str| (name.to_string(), Version::parse("1.0.0"));
+        let fresh = [krate("slow"), krate("big"), krate("fine"), krate("new")];
+        let diagnoses = HashMap::from([
+            (key("slow"), error("Timeout")),
+            (key("big"), error("OOM")),
+            (key("fine"), Status::Passing),
+        ]);
+        let policies = Policies {
+            rerun: false,
+            max_age_days: 30,
+            rerun_status: vec![StatusClass::Timeout, StatusClass::Oom],
+            rerun_toolchain_changed: false,
+            rerun_mismatched: false,
+        };
+        let mut selected = policies.by_status(&fresh, &diagnoses);
+        selected.sort();
+        assert_eq!(selected, [key("big"), key("slow")]);
+    }
+}
@@ -0,0 +1,266 @@
+//! The patterns we look for in logs, and what we conclude when we find them.
+//!
+//! The default rule set is `rules.json`, embedded in the binary. It can be replaced at runtime
+//! with `--rules`, so that a change in Miri or ASan's wording can be handled without a rebuild.
+
+use crate::Tool;
+use color_eyre::eyre::{ensure, Result};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static EMBEDDED: &str = include_str!("rules.json");
+
+static RULES: OnceCell<Rules> = OnceCell::new();
+
+/// Which part of a log a rule is matched against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    /// The whole log; decides the status of the crate
+    Log,
+    /// The line of each Miri report that contains "Undefined Behavior: "
+    Miri,
+    /// A Miri report that was classified as a Stacked Borrows violation
+    StackedBorrows,
+    /// Each line that contains "ERROR: AddressSanitizer: "
+    Asan,
+    /// Each line that contains "unsupported operation: "
+    Unsupported,
+    /// The whole log of a crate that failed to build
+    Build,
+}
+
+/// What to conclude when a rule matches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// The rule's kind is a cause of UB
+    #[default]
+    Ub,
+    /// The crate failed with the rule's kind as the reason
+    Error,
+    /// The log contains Miri reports, each of which is classified by the `miri` stage
+    MiriReports,
+    /// The log contains ASan reports, each of which is classified by the `asan` stage
+    AsanReports,
+    /// The log contains operations Miri does not support, each of which is classified by the
+    /// `unsupported` stage
+    UnsupportedReports,
+    /// The crate failed to build, which is classified by the `build` stage
+    BuildFailure,
+    /// Classify the report further with the `stacked-borrows` stage
+    StackedBorrows,
+    /// The kind is whatever Miri says was encountered
+    TypeValidation,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub stage: Stage,
+    /// Every one of these must be present
+    #[serde(default)]
+    pub all: Vec<String>,
+    /// At least one of these must be present, if there are any
+    #[serde(default)]
+    pub any: Vec<String>,
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default)]
+    pub action: Action,
+    /// Rules with a higher priority are tried first
+    pub priority: i32,
+    /// Only apply this rule to logs from this tool
+    #[serde(default)]
+    pub tool: Option<Tool>,
+    /// For the `stacked-borrows` stage, match any line of the report instead of only the first
+    #[serde(default)]
+    pub whole_report: bool,
+    /// A regex whose first matching group is the detail of the finding, such as the name of a
+    /// missing library
+    #[serde(default)]
+    capture: Option<String>,
+    #[serde(skip)]
+    capture_regex: Option<Regex>,
+    #[serde(skip)]
+    hits: AtomicUsize,
+}
+
+impl Rule {
+    fn matches(&self, text: &str) -> bool {
+        self.all.iter().all(|p| text.contains(p.as_str()))
+            && (self.any.is_empty() || self.any.iter().any(|p| text.contains(p.as_str())))
+    }
+
+    /// The text that identifies this rule in a log, for linking to it.
+    pub fn marker(&self) -> &str {
+        self.all
+            .first()
+            .or_else(|| self.any.first())
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+    }
+
+    /// The detail this rule's `capture` extracts from the text, if any.
+    pub fn capture(&self, text: &str) -> Option<String> {
+        let captures = self.capture_regex.as_ref()?.captures(text)?;
+        captures
+            .iter()
+            .skip(1)
+            .flatten()
+            .next()
+            .map(|m| m.as_str().to_string())
+    }
+
+    #[cfg(test)]
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn parse(json: &str) -> Result<Self> {
+        let mut rules: Vec<Rule> = serde_json::from_str(json)?;
+        for rule in &mut rules {
+            ensure!(
+                !rule.all.is_empty() || !rule.any.is_empty(),
+                "The rule for {:?} has no patterns",
+                rule.kind
+            );
+            if let Some(capture) = &rule.capture {
+                rule.capture_regex = Some(Regex::new(capture)?);
+            }
+        }
+        // Stable, so rules with equal priority are tried in the order they are written
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+        Ok(Self { rules })
+    }
+
+    pub fn embedded() -> Self {
+        Self::parse(EMBEDDED).expect("the embedded rules are invalid")
+    }
+
+    #[cfg(test)]
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+
+    fn candidates(&self, tool: Tool, stage: Stage) -> impl Iterator<Item = &Rule> {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.stage == stage && rule.tool.is_none_or(|t| t == tool))
+    }
+
+    /// Finds the highest-priority rule of this stage which matches the text.
+    pub fn find(&self, tool: Tool, stage: Stage, text: &str) -> Option<&Rule> {
+        let rule = self
+            .candidates(tool, stage)
+            .find(|rule| rule.matches(text))?;
+        rule.hits.fetch_add(1, Ordering::Relaxed);
+        Some(rule)
+    }
+
+    /// Like `find`, but rules which are not `whole_report` only look at the first line.
+    pub fn find_in_report(&self, tool: Tool, stage: Stage, lines: &[&str]) -> Option<&Rule> {
+        let rule = self.candidates(tool, stage).find(|rule| {
+            if rule.whole_report {
+                lines.iter().any(|line| rule.matches(line))
+            } else {
+                lines.first().is_some_and(|line| rule.matches(line))
+            }
+        })?;
+        rule.hits.fetch_add(1, Ordering::Relaxed);
+        Some(rule)
+    }
+
+    /// The markers of every rule which reports UB for this tool, in priority order.
+    pub fn ub_markers(&self, tool: Tool) -> impl Iterator<Item = &str> {
+        self.candidates(tool, Stage::Log)
+            .filter(|rule| {
+                matches!(
+                    rule.action,
+                    Action::Ub | Action::MiriReports | Action::AsanReports
+                )
+            })
+            .map(|rule| rule.marker())
+    }
+}
+
+/// Use the rules in this file instead of the embedded ones. Must be called before any logs are
+/// diagnosed or rendered.
+pub fn load_override(path: &Path) -> Result<()> {
+    let rules = Rules::parse(&std::fs::read_to_string(path)?)?;
+    ensure!(
+        RULES.set(rules).is_ok(),
+        "The rules were already loaded before the override"
+    );
+    Ok(())
+}
+
+pub fn rules() -> &'static Rules {
+    RULES.get_or_init(Rules::embedded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnose::diagnose_status;
+    use std::fs;
+
+    #[test]
+    fn embedded_rules_parse() {
+        Rules::embedded();
+    }
+
+    /// Diagnoses every log in the corpus and fails on any rule that never matched, which is
+    /// either dead or lacks an example in the corpus.
+    #[test]
+    fn unmatched_rules() {
+        let rules = Rules::embedded();
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+        let mut logs = 0;
+        for tool_dir in fs::read_dir(corpus).unwrap() {
+            let tool_dir = tool_dir.unwrap();
+            let tool: Tool = tool_dir.file_name().to_str().unwrap().parse().unwrap();
+            for log in fs::read_dir(tool_dir.path()).unwrap() {
+                let path = log.unwrap().path();
+                if path.extension().is_some_and(|ext| ext == "log") {
+                    diagnose_status(&rules, tool, &fs::read(path).unwrap());
+                    logs += 1;
+                }
+            }
+        }
+        assert!(logs > 0, "the corpus is empty");
+
+        let unmatched = rules
+            .iter()
+            .filter(|rule| rule.hits() == 0)
+            .map(|rule| {
+                format!(
+                    "    {:?} rule {:?} ({:?}, priority {})",
+                    rule.stage,
+                    rule.marker(),
+                    rule.kind,
+                    rule.priority
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            unmatched.is_empty(),
+            "{} of {} rules never matched, add a log that they match with \
+            `diagnose --save-as`:\n{}",
+            unmatched.len(),
+            rules.iter().count(),
+            unmatched.join("\n")
+        );
+    }
+}
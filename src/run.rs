@@ -1,31 +1,39 @@
-use crate::{client::Client, render, Crate, Tool, Version};
+use crate::{
+    budget::{Budget, Limits, Overrides},
+    client::Client,
+    cpus::{Cpus, Layout},
+    diagnose::diagnose_status,
+    provenance,
+    queue::{self, Job, Queue, Remote},
+    render, repro, rerun,
+    rules::rules,
+    sandbox::{Sandbox, Spec},
+    schedule::{format_secs, Durations, Shard},
+    Crate, Tool, Version,
+};
 use clap::Parser;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{ensure, Result};
 use once_cell::sync::Lazy;
 use std::{
-    collections::HashMap,
-    fs,
-    process::Stdio,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    signal::unix::{signal, SignalKind},
     task::JoinSet,
 };
 use uuid::Uuid;
 
 static TEST_END_DELIMITER: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
 
-// These crates generate gigabytes of output then don't build.
-const IGNORED_CRATES: &[&str] = &[
-    "clacks_mtproto",
-    "stdweb",
-    "wayland-raw-protocol-bindings",
-    "pleingres",
-    "gdnative-bindings-lily",
-];
-
-#[derive(Parser, Clone)]
+#[derive(Parser, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Args {
     /// Run the top `n` most-recently-downloaded crates
     #[clap(long, conflicts_with = "crate_list")]
@@ -35,21 +43,108 @@ pub struct Args {
     #[clap(long, conflicts_with = "crates")]
     crate_list: Option<String>,
 
+    /// The memory each crate gets at first
     #[clap(long, default_value_t = 8)]
     memory_limit_gb: usize,
 
+    /// The most memory a crate that ran out of memory is rerun with
+    #[clap(long, default_value_t = 32)]
+    max_memory_limit_gb: usize,
+
+    /// How long each command run for a crate can take at first
+    #[clap(long, default_value_t = 60 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout_secs: u64,
+
+    /// The longest each command run for a crate that timed out is rerun with
+    #[clap(long, default_value_t = 4 * 60 * 60)]
+    max_timeout_secs: u64,
+
+    /// A JSON file of the budgets of crates that need more than the others, like
+    /// `{"some-crate": {"memory_gb": 32, "timeout_secs": 7200}}`
+    #[clap(long)]
+    budgets: Option<PathBuf>,
+
+    /// Kill a worker that has spent this long on one crate, in case the timeouts inside the
+    /// container don't manage to. This is scaled along with the time budget of the crate.
+    #[clap(long, default_value_t = 4 * 60 * 60)]
+    crate_timeout_secs: u64,
+
+    /// Kill a worker that has printed this much for one crate
+    #[clap(long, default_value_t = 256)]
+    max_output_mb: usize,
+
+    /// Only store the start and end of logs bigger than this
+    #[clap(long, default_value_t = 16)]
+    max_stored_output_mb: usize,
+
+    /// How many times to rerun a crate whose worker crashed before giving up on it. Crates that
+    /// are given up on are quarantined: future runs skip that version until its `name@version` is
+    /// removed from `<tool>/quarantine.json` in the bucket.
+    #[clap(long, default_value_t = 2)]
+    crash_retries: usize,
+
+    /// How long to wait before the first rerun of a crate whose worker crashed, doubled for each
+    /// one after that
+    #[clap(long, default_value_t = 30)]
+    crash_backoff_secs: u64,
+
+    /// A file of crate names not to run, one per line
+    #[clap(long, default_value = "skip-list.txt")]
+    skip_list: PathBuf,
+
+    /// Skip crates that have produced too much output in this many runs. Crates that have done so
+    /// fewer times are run last.
+    #[clap(long, default_value_t = 2)]
+    skip_oversized_after: usize,
+
+    #[command(flatten)]
+    pub rerun: rerun::Policies,
+
+    /// Continue a run that was stopped with Ctrl-C or SIGTERM, with the crates and options it
+    /// saved. The tool and bucket must match the ones it was run with.
     #[clap(long)]
-    rerun: bool,
+    #[serde(skip)]
+    resume: bool,
+
+    /// Where a run that is stopped saves what it did not get to
+    #[clap(long, default_value = "run-state.json")]
+    state_file: PathBuf,
 
     #[clap(long)]
     pub tool: Tool,
 
+    #[command(flatten)]
+    pub sandbox: Sandbox,
+
     #[clap(long)]
     pub bucket: String,
 
+    /// How many crates to run at once, one for each set of `--cpus-per-job` CPUs by default
     #[clap(long)]
     jobs: Option<usize>,
 
+    /// How many CPUs each worker is pinned to. They are all on one NUMA node if one has that many.
+    #[clap(long, default_value_t = 1)]
+    cpus_per_job: usize,
+
+    /// Give crates that took at least `--heavy-after-secs` the last time they ran this many CPUs,
+    /// shared with the workers next to theirs on the same NUMA node
+    #[clap(long)]
+    heavy_cpus: Option<usize>,
+
+    /// How long a crate has to have taken to get `--heavy-cpus`
+    #[clap(long, default_value_t = 30 * 60)]
+    heavy_after_secs: u64,
+
+    /// Run the crates a `serve-queue` coordinator at this URL hands out, instead of picking them
+    #[clap(long, conflicts_with_all = ["crates", "crate_list", "shard", "resume"])]
+    pub queue_url: Option<String>,
+
+    /// Only run this machine's part of the crates, like `2/4` for the second of four machines
+    /// that share the bucket
+    #[clap(long)]
+    shard: Option<Shard>,
+
     #[clap(long)]
     rev: bool,
 
@@ -61,6 +156,27 @@ pub struct Args {
     target: String,
 }
 
+impl Args {
+    /// How many crates to run at once
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| Layout::detect().slots(self.cpus_per_job).len().max(1))
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            base: Budget {
+                memory_gb: self.memory_limit_gb,
+                timeout_secs: self.timeout_secs,
+            },
+            max: Budget {
+                memory_gb: self.max_memory_limit_gb,
+                timeout_secs: self.max_timeout_secs,
+            },
+        }
+    }
+}
+
 async fn build_crate_list(args: &Args, client: &Client) -> Result<Vec<Crate>> {
     let all_crates = client.get_crate_versions().await?;
     let crates = if let Some(crate_list) = &args.crate_list {
@@ -83,7 +199,7 @@ async fn build_crate_list(args: &Args, client: &Client) -> Result<Vec<Crate>> {
                 });
             }
         }
-        crates.sort_by(|a, b| a.recent_downloads.cmp(&b.recent_downloads));
+        crates.sort_by_key(|c| c.recent_downloads);
         crates
     } else if let Some(crate_count) = args.crates {
         let mut crates = all_crates;
@@ -95,29 +211,88 @@ async fn build_crate_list(args: &Args, client: &Client) -> Result<Vec<Crate>> {
     Ok(crates)
 }
 
-#[tokio::main]
-pub async fn run(args: Args) -> Result<()> {
-    let dockerfile = if std::env::var_os("CI").is_some() {
-        "docker/Dockerfile.ci"
-    } else {
-        "docker/Dockerfile"
-    };
-    let status = std::process::Command::new("docker")
-        .args(["build", "-t", "crater-at-home", "-f", dockerfile, "docker/"])
-        .status()?;
-    color_eyre::eyre::ensure!(status.success(), "docker image build failed!");
+/// The crates a run with these options should run, in reverse order so that they can be popped.
+pub async fn build_queue_for(args: &Args) -> Result<Vec<Job>> {
+    let client = Arc::new(Client::new(args.tool, &args.bucket).await?);
+    let oversized = Strikes::download(&client, args.tool.oversized_path()).await?;
+    let quarantine = Strikes::download(&client, args.tool.quarantine_path()).await?;
+    let durations = Durations::download(&client, args.tool.durations_path()).await?;
+    build_queue(args, &client, &oversized, &quarantine, &durations).await
+}
+
+/// Jobs for these crates, leaving out the ones this run should not run, in reverse order so that
+/// they can be popped.
+pub async fn jobs_for(args: &Args, client: &Client, crates: Vec<Crate>) -> Result<Vec<Job>> {
+    let oversized = Strikes::download(client, args.tool.oversized_path()).await?;
+    let quarantine = Strikes::download(client, args.tool.quarantine_path()).await?;
+    let durations = Durations::download(client, args.tool.durations_path()).await?;
+    into_jobs(args, crates, &oversized, &quarantine, &durations).await
+}
 
+/// The crates this run should run, in reverse order so that they can be popped.
+async fn build_queue(
+    args: &Args,
+    client: &Arc<Client>,
+    oversized: &Strikes,
+    quarantine: &Strikes,
+    durations: &Durations,
+) -> Result<Vec<Job>> {
     log::info!("Figuring out what crates have a build log already");
-    let client = Arc::new(Client::new(args.tool, &args.bucket).await?);
-    let mut crates = build_crate_list(&args, &client).await?;
-    if !args.rerun {
+    let mut crates = build_crate_list(args, client).await?;
+    // Before anything that changes as the other shards run
+    if let Some(shard) = args.shard {
+        let all = crates.len();
+        let durations = Shard::pinned_durations(client, args.tool).await?;
+        crates = shard.select(crates, &durations);
+        log::info!("Shard {} has {} of {} crates", shard, crates.len(), all);
+    }
+    if !args.rerun.rerun {
         let finished_crates = client
-            .list_finished_crates(Some(time::Duration::days(30)))
+            .list_finished_crates(Some(time::Duration::days(args.rerun.max_age_days)))
+            .await?;
+        let rerun = args
+            .rerun
+            .select(client, &finished_crates, &args.sandbox)
             .await?;
+        log::info!(
+            "{} crates have a recent result, {} of which will be rerun",
+            finished_crates.len(),
+            rerun.len()
+        );
+        let finished = finished_crates
+            .into_iter()
+            .map(|c| (c.name, c.version))
+            .filter(|key| !rerun.contains(key))
+            .collect::<HashSet<_>>();
+        crates.retain(|krate| !finished.contains(&(krate.name.clone(), krate.version.clone())));
+    }
+    into_jobs(args, crates, oversized, quarantine, durations).await
+}
+
+async fn into_jobs(
+    args: &Args,
+    mut crates: Vec<Crate>,
+    oversized: &Strikes,
+    quarantine: &Strikes,
+    durations: &Durations,
+) -> Result<Vec<Job>> {
+    let skip_list = read_skip_list(&args.skip_list)?;
+    {
+        let counts = oversized.counts.lock().await;
+        let quarantined = quarantine.counts.lock().await;
+        let count = |krate: &Crate| counts.get(&krate.name).copied().unwrap_or(0);
         crates.retain(|krate| {
-            !finished_crates
-                .iter()
-                .any(|c| c.name == krate.name && c.version == krate.version)
+            !skip_list.contains(&krate.name)
+                && count(krate) < args.skip_oversized_after
+                && !quarantined.contains_key(&quarantine_key(krate))
+        });
+        // Longest first, so that the slow crates don't hold up the end of the run, except that
+        // crates which produced too much output go last
+        crates.sort_by_cached_key(|krate| {
+            (
+                count(krate) > 0,
+                std::cmp::Reverse(durations.estimate(krate)),
+            )
         });
     }
 
@@ -125,118 +300,916 @@ pub async fn run(args: Args) -> Result<()> {
         // We are going to pop crates from this, so we now need to invert the order
         crates = crates.into_iter().rev().collect::<Vec<_>>();
     }
-    let crates = Arc::new(Mutex::new(crates));
+    let overrides = match &args.budgets {
+        Some(path) => Overrides::read(path)?,
+        None => Overrides::default(),
+    };
+    Ok(crates
+        .into_iter()
+        .map(|krate| Job {
+            budget: overrides.initial(&krate, &args.limits()),
+            estimate_secs: durations.estimate(&krate),
+            crashes: 0,
+            not_before: None,
+            krate,
+        })
+        .collect())
+}
+
+#[tokio::main]
+pub async fn run(args: Args) -> Result<()> {
+    let (args, resumed) = resume(args)?;
+    let image = args.sandbox.build_image()?;
+    let queue = match (resumed, &args.queue_url) {
+        (Some(crates), _) => Queue::local(crates, args.jobs()),
+        (None, Some(url)) => Queue::Remote(Remote::new(url)),
+        (None, None) => Queue::local(build_queue_for(&args).await?, args.jobs()),
+    };
+    execute(args, image, Arc::new(queue)).await
+}
+
+/// With `--resume`, the options and the crates left of the run that was stopped.
+pub fn resume(args: Args) -> Result<(Args, Option<Vec<Job>>)> {
+    if !args.resume {
+        return Ok((args, None));
+    }
+    let state: State = serde_json::from_slice(&fs::read(&args.state_file)?)?;
+    ensure!(
+        state.args.tool == args.tool && state.args.bucket == args.bucket,
+        "{} is a run of {} into {}",
+        args.state_file.display(),
+        state.args.tool,
+        state.args.bucket
+    );
+    log::info!(
+        "Resuming the run in {} with {} crates left",
+        args.state_file.display(),
+        state.queue.len()
+    );
+    let resumed_args = Args {
+        resume: true,
+        state_file: args.state_file,
+        ..state.args
+    };
+    Ok((resumed_args, Some(state.queue)))
+}
+
+/// Runs the crates from `queue` in workers of `image` until there are none left or the run is
+/// stopped.
+pub async fn execute(args: Args, image: String, queue: Arc<Queue>) -> Result<()> {
+    let host = provenance::Host {
+        tool: args.tool,
+        target: args.target.clone(),
+        image,
+        toolchain: provenance::toolchain(&args.sandbox)?.to_string(),
+        hostname: fs::read_to_string("/proc/sys/kernel/hostname")
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    };
+
+    let client = Arc::new(Client::new(args.tool, &args.bucket).await?);
+    let oversized = Arc::new(Strikes::download(&client, args.tool.oversized_path()).await?);
+    let quarantine = Arc::new(Strikes::download(&client, args.tool.quarantine_path()).await?);
+    let durations = Arc::new(Durations::download(&client, args.tool.durations_path()).await?);
+    let shutdown = Arc::new(Shutdown::default());
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.listen().await }
+    });
+
+    let layout = Arc::new(Layout::detect());
+    let slots = layout.slots(args.cpus_per_job);
+    ensure!(
+        !slots.is_empty(),
+        "This machine has fewer than {} CPUs",
+        args.cpus_per_job
+    );
+    if args.jobs() > slots.len() {
+        log::warn!(
+            "Running {} workers on {} sets of CPUs, so some share them",
+            args.jobs(),
+            slots.len()
+        );
+    }
 
     let mut tasks = JoinSet::new();
-    for cpu in 0..args.jobs.unwrap_or_else(num_cpus::get) {
-        let crates = crates.clone();
+    for index in 0..args.jobs() {
+        let cpus = slots[index % slots.len()].clone();
+        let layout = layout.clone();
+        let queue = queue.clone();
         let args = args.clone();
         let client = client.clone();
+        let host = host.clone();
+        let oversized = oversized.clone();
+        let quarantine = quarantine.clone();
+        let durations = durations.clone();
+        let shutdown = shutdown.clone();
+        let worker_name = format!("{}-{}", host.hostname, index);
 
-        let test_end_delimiter_with_dashes = format!("-{}-\n", *TEST_END_DELIMITER).into_bytes();
-
-        let mut child = spawn_worker(&args, cpu);
+        let mut worker = spawn_worker(&args, &cpus);
 
         tasks.spawn(async move {
             loop {
-                let mut stdout = BufReader::new(child.stdout.as_mut().unwrap());
-                let krate = match crates.lock().unwrap().pop() {
-                    None => break,
-                    Some(krate) => krate,
+                let Some((lease, job)) = queue.next(&worker_name, || shutdown.draining()).await
+                else {
+                    break;
                 };
+                let krate = &job.krate;
 
-                if IGNORED_CRATES.contains(&&krate.name[..]) {
-                    continue;
-                }
+                log::info!("Running {} {} with {}", krate.name, krate.version, job.budget);
+                let started = time::OffsetDateTime::now_utc();
+                let timer = std::time::Instant::now();
+
+                let job_cpus = match args.heavy_cpus {
+                    Some(count) if job.estimate_secs >= args.heavy_after_secs => {
+                        layout.widen(&cpus, count)
+                    }
+                    _ => cpus.clone(),
+                };
 
-                log::info!("Running {} {}", krate.name, krate.version);
-
-                child
-                    .stdin
-                    .as_mut()
-                    .unwrap()
-                    .write_all(format!("{}@{}\n", krate.name, krate.version).as_bytes())
-                    .await
-                    .unwrap();
-
-                let mut output = Vec::new();
-                loop {
-                    let bytes_read = stdout.read_until(b'\n', &mut output).await.unwrap();
-                    if output.ends_with(&test_end_delimiter_with_dashes) {
-                        output.truncate(output.len() - test_end_delimiter_with_dashes.len() - 1);
-                        break;
+                let attempt = tokio::select! {
+                    attempt = run_crate(&mut worker, &job, &job_cpus, &args) => Some(attempt),
+                    _ = shutdown.aborted() => None,
+                };
+                let Some(attempt) = attempt else {
+                    log::info!("Stopping {} {}", krate.name, krate.version);
+                    // On top, so that it is the first to run when the run is resumed
+                    queue.release(lease, job).await;
+                    break;
+                };
+                if attempt.oversized() {
+                    oversized.record(&client, &krate.name).await;
+                }
+                let mut output = attempt.output;
+                match attempt.ending {
+                    Ending::Finished => {}
+                    Ending::Exceeded(exceeded) => {
+                        log::warn!(
+                            "{} {} exceeded {}, replacing its worker",
+                            krate.name,
+                            krate.version,
+                            exceeded
+                        );
+                        worker.kill().await;
+                        worker = spawn_worker(&args, &cpus);
+                        // Diagnosed by the rules for this message
+                        output.extend_from_slice(
+                            format!("\ncrater-at-home: killed after exceeding {exceeded}\n")
+                                .as_bytes(),
+                        );
+                    }
+                    Ending::StillOnline => {
+                        log::warn!(
+                            "Failed to take {} off the network, running {} {} again later",
+                            worker.name,
+                            krate.name,
+                            krate.version
+                        );
+                        worker.kill().await;
+                        worker = spawn_worker(&args, &cpus);
+                        // Not the crate's fault, so not a crash, but the runtime may need time
+                        // to recover all the same
+                        let retry = Job {
+                            not_before: Some(queue::unix_now() + args.crash_backoff_secs),
+                            ..job
+                        };
+                        queue.complete(lease, Some(retry)).await;
+                        continue;
                     }
-                    if bytes_read == 0 {
-                        break;
+                    Ending::Crashed => {
+                        let status = worker.exit_status().await;
+                        worker = spawn_worker(&args, &cpus);
+                        let crashes = job.crashes + 1;
+                        if crashes > args.crash_retries {
+                            log::warn!(
+                                "{} {} crashed {} workers, quarantining it",
+                                krate.name,
+                                krate.version,
+                                crashes
+                            );
+                            quarantine.record(&client, &quarantine_key(krate)).await;
+                            // Diagnosed by the rules for this message
+                            output.extend_from_slice(
+                                format!(
+                                    "\ncrater-at-home: the worker crashed {crashes} times, last with {status}\n"
+                                )
+                                .as_bytes(),
+                            );
+                        } else {
+                            let backoff = args.crash_backoff_secs << (crashes - 1);
+                            log::warn!(
+                                "The worker running {} {} crashed with {}, retrying in {}",
+                                krate.name,
+                                krate.version,
+                                status,
+                                format_secs(backoff)
+                            );
+                            // Back in the queue rather than waiting here, so that this worker
+                            // runs other crates in the meantime
+                            let retry = Job {
+                                crashes,
+                                not_before: Some(queue::unix_now() + backoff),
+                                ..job
+                            };
+                            queue.complete(lease, Some(retry)).await;
+                            continue;
+                        }
                     }
                 }
-                log::debug!("{:?}", output);
 
-                if let Ok(Some(_)) = child.try_wait() {
-                    log::warn!("A worker crashed! Standing up a new one...");
-                    child = spawn_worker(&args, cpu);
-                    // Don't upload logs for crashed runs
+                let secs = timer.elapsed().as_secs();
+                durations.record(&client, krate, secs).await;
+
+                let status = diagnose_status(rules(), args.tool, &output);
+                if let Some(budget) = job.budget.escalate(&status, &args.limits()) {
+                    log::info!(
+                        "{} {} ran out of {}, running it again later with {}",
+                        krate.name,
+                        krate.version,
+                        job.budget,
+                        budget
+                    );
+                    // Put it at the back of the queue so that it does not hold up the rest
+                    let retry = Job {
+                        krate: job.krate,
+                        budget,
+                        estimate_secs: secs,
+                        crashes: job.crashes,
+                        not_before: None,
+                    };
+                    queue.complete(lease, Some(retry)).await;
                     continue;
                 }
 
-                // Render HTML for the stderr/stdout we captured
-                let rendered = render::render_crate(&krate, &output);
-
-                // Upload both
-                client.upload_raw(&krate, output).await.unwrap();
-                client
-                    .upload_html(&krate, rendered.into_bytes())
-                    .await
-                    .unwrap();
-
-                log::info!("Finished {} {}", krate.name, krate.version);
+                upload_result(&client, &host, &args, &job, started, output).await;
+                queue.complete(lease, None).await;
+                match queue.eta() {
+                    Some(eta) => log::info!(
+                        "Finished {} {} in {}; {}",
+                        krate.name,
+                        krate.version,
+                        format_secs(secs),
+                        eta
+                    ),
+                    None => log::info!(
+                        "Finished {} {} in {}",
+                        krate.name,
+                        krate.version,
+                        format_secs(secs)
+                    ),
+                }
             }
+            // Not needed when the worker ran out of crates, but the container would be left
+            // running if it stopped for a shutdown
+            worker.kill().await;
         });
     }
 
     while let Some(task) = tasks.join_next().await {
         task?;
     }
+    durations.save(&client).await;
+
+    let queue = queue.take_remaining();
+    if shutdown.draining() && !queue.is_empty() {
+        let state = State {
+            args: args.clone(),
+            queue,
+        };
+        fs::write(&args.state_file, serde_json::to_vec(&state)?)?;
+        log::info!(
+            "Saved the {} crates that were not run to {}, continue with `--resume`",
+            state.queue.len(),
+            args.state_file.display()
+        );
+        return Ok(());
+    } else if args.resume {
+        fs::remove_file(&args.state_file)?;
+    }
 
     log::info!("done!");
 
     Ok(())
 }
 
-fn spawn_worker(args: &Args, cpu: usize) -> tokio::process::Child {
-    let mut cmd = tokio::process::Command::new("docker");
-    cmd.args([
-        "run",
-        "--rm",
-        "--interactive",
-        // Pin the build to a single CPU; this also ensures that anything doing
-        // make -j $(nproc)
-        // will not spawn processes appropriate for the host.
-        &format!("--cpuset-cpus={cpu}"),
-        // We set up our filesystem as read-only, but with 3 exceptions
-        "--read-only",
-        // The directory we are building in (not just its target dir!) is all writable
-        "--volume=/build",
-        // rustdoc tries to write to and executes files in /tmp, odd move but whatever
-        "--tmpfs=/tmp:exec",
-        // The default cargo registry location; we download dependences in the sandbox
-        "--tmpfs=/root/.cargo/registry",
-        // cargo-miri builds a sysroot under /root/.cache, so why not make it all writeable
-        "--tmpfs=/root/.cache:exec",
-        &format!("--env=TEST_END_DELIMITER={}", *TEST_END_DELIMITER),
-        &format!("--env=TOOL={}", args.tool),
-        &format!("--env=TARGET={}", args.target),
-    ]);
-    cmd.args([
-        // Enforce the memory limit
-        &format!("--memory={}g", args.memory_limit_gb),
-        // Setting --memory-swap to the same value turns off swap
-        &format!("--memory-swap={}g", args.memory_limit_gb),
-        "crater-at-home:latest",
-    ])
-    .stdin(Stdio::piped())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped())
-    .spawn()
-    .unwrap()
+/// What a run that was stopped needs to pick up where it left off.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct State {
+    args: Args,
+    queue: Vec<Job>,
+}
+
+/// What Ctrl-C or SIGTERM have asked for. The first one stops new crates from starting, and the
+/// second stops the crates that are running too.
+#[derive(Default)]
+struct Shutdown {
+    signals: AtomicUsize,
+    abort: tokio::sync::Notify,
+}
+
+impl Shutdown {
+    async fn listen(&self) {
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            self.signal();
+        }
+    }
+
+    fn signal(&self) {
+        if self.signals.fetch_add(1, Ordering::SeqCst) == 0 {
+            log::warn!(
+                "Stopping once the running crates are done; interrupt again to stop them now"
+            );
+        } else {
+            log::warn!("Stopping the running crates");
+            self.abort.notify_waiters();
+        }
+    }
+
+    fn draining(&self) -> bool {
+        self.signals.load(Ordering::SeqCst) > 0
+    }
+
+    /// Completes when the running crates should be stopped.
+    async fn aborted(&self) {
+        loop {
+            let notified = self.abort.notified();
+            if self.signals.load(Ordering::SeqCst) > 1 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// How reading the output of one crate ended.
+enum Ending {
+    /// The worker finished the crate
+    Finished,
+    /// The output ended without the worker finishing the crate, so it died
+    Crashed,
+    /// The crate went past one of our limits
+    Exceeded(Limit),
+    /// The worker could not be taken off the network, so the crate was not built
+    StillOnline,
+}
+
+enum Limit {
+    /// In seconds, scaled for the crate
+    Time(u64),
+    /// In MB
+    Output(usize),
+}
+
+/// Diagnosed by the rules for these messages
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Time(secs) => write!(f, "the time limit of {secs} seconds"),
+            Limit::Output(mb) => write!(f, "the output limit of {mb} MB"),
+        }
+    }
+}
+
+struct Attempt {
+    output: Vec<u8>,
+    ending: Ending,
+    /// Whether the middle of the output was dropped
+    truncated: bool,
+}
+
+impl Attempt {
+    /// Whether this counts against the crate as too much output. Running out of time doesn't;
+    /// plenty of crates are just slow.
+    fn oversized(&self) -> bool {
+        self.truncated || matches!(self.ending, Ending::Exceeded(Limit::Output(_)))
+    }
+}
+
+/// Has the worker run one crate, and reads what it printed.
+async fn run_crate(worker: &mut Worker, job: &Job, cpus: &Cpus, args: &Args) -> Attempt {
+    let test_end_delimiter_with_dashes = format!("-{}-\n", *TEST_END_DELIMITER).into_bytes();
+    let fetched_marker = format!("-{}-fetched-\n", *TEST_END_DELIMITER).into_bytes();
+    worker.set_memory(job.budget.memory_gb).await;
+    worker.set_cpus(cpus).await;
+    let crate_timeout_secs = args.crate_timeout_secs * job.budget.timeout_secs / args.timeout_secs;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(crate_timeout_secs);
+    let max_output = args.max_output_mb * 1024 * 1024;
+    let mut output = CappedOutput::new(args.max_stored_output_mb * 1024 * 1024);
+    let given_up = |ending| Attempt {
+        output: Vec::new(),
+        ending,
+        truncated: false,
+    };
+
+    let krate = format!("{}@{}", job.krate.name, job.krate.version);
+    let mut request = format!("{} {}", krate, job.budget.timeout_secs);
+    if worker.sandbox.fetches_separately() {
+        let env = worker_env(args);
+        let spec = Spec {
+            name: &worker.name,
+            cpus,
+            memory_gb: job.budget.memory_gb,
+            env: &env,
+        };
+        let fetched = match worker.sandbox.fetch(&spec, &krate, job.budget.timeout_secs) {
+            Ok(mut fetch) => tokio::time::timeout_at(deadline, fetch.output())
+                .await
+                .map(|output| output.map_err(color_eyre::Report::from)),
+            Err(e) => Ok(Err(e)),
+        };
+        let mut fetched = match fetched {
+            Ok(Ok(fetched)) => fetched.stdout,
+            Ok(Err(e)) => {
+                log::warn!("Failed to fetch {} for {}: {}", krate, worker.name, e);
+                worker.kill().await;
+                return given_up(Ending::Crashed);
+            }
+            Err(_) => return given_up(Ending::Exceeded(Limit::Time(crate_timeout_secs))),
+        };
+        if let Some(crate_args) = split_off_section(&mut fetched, "args") {
+            request.push(' ');
+            request.push_str(String::from_utf8_lossy(&crate_args).trim());
+        }
+        if !fetched.is_empty() {
+            fetched.push(b'\n');
+            output.push(&fetched);
+        }
+    } else if !worker.set_network(true).await {
+        // It could not download the crate, which would look like the crate passing
+        log::warn!("Failed to connect {} to the network", worker.name);
+        worker.kill().await;
+        return given_up(Ending::Crashed);
+    }
+    request.push('\n');
+    let stdin = worker.child.stdin.as_mut().unwrap();
+    if stdin.write_all(request.as_bytes()).await.is_err() {
+        return given_up(Ending::Crashed);
+    }
+
+    let mut stdout = BufReader::new(worker.child.stdout.as_mut().unwrap());
+    let mut line = Vec::new();
+    let ending = loop {
+        let remaining = max_output.saturating_sub(output.total);
+        if remaining == 0 {
+            break Ending::Exceeded(Limit::Output(args.max_output_mb));
+        }
+        // A crate can print forever without a newline, so bound each read too
+        let mut limited = (&mut stdout).take(remaining as u64);
+        line.clear();
+        let read = limited.read_until(b'\n', &mut line);
+        let Ok(bytes_read) = tokio::time::timeout_at(deadline, read).await else {
+            break Ending::Exceeded(Limit::Time(crate_timeout_secs));
+        };
+        let bytes_read = bytes_read.unwrap_or(0);
+        if line.ends_with(&fetched_marker) {
+            line.truncate(line.len() - fetched_marker.len());
+            output.push(&line);
+            // The crate and its dependencies are downloaded, so nothing from here on needs the
+            // network. Fields of the worker, because stdout is borrowed.
+            if worker.connected {
+                worker.connected = !worker.sandbox.set_network(&worker.name, false).await;
+                if worker.connected {
+                    // Without telling it to go on, so that the crate is not built
+                    break Ending::StillOnline;
+                }
+            }
+            let stdin = worker.child.stdin.as_mut().unwrap();
+            if stdin.write_all(b"\n").await.is_err() {
+                break Ending::Crashed;
+            }
+            continue;
+        }
+        if line.ends_with(&test_end_delimiter_with_dashes) {
+            line.truncate(line.len() - test_end_delimiter_with_dashes.len());
+            output.push(&line);
+            break Ending::Finished;
+        }
+        output.push(&line);
+        if bytes_read == 0 {
+            break Ending::Crashed;
+        }
+    };
+    let (mut output, truncated) = output.finish();
+    // The newline before the delimiter is not part of the output
+    if output.ends_with(b"\n") {
+        output.pop();
+    }
+    log::debug!("{:?}", output);
+    Attempt {
+        output,
+        ending,
+        truncated,
+    }
+}
+
+/// Stores the log of a crate, the HTML rendering of it, and what the worker reported the result
+/// depends on.
+async fn upload_result(
+    client: &Client,
+    host: &provenance::Host,
+    args: &Args,
+    job: &Job,
+    started: time::OffsetDateTime,
+    mut output: Vec<u8>,
+) {
+    let krate = &job.krate;
+    // The worker reports the flags and lockfile after the log
+    let text = |bytes: Option<Vec<u8>>| bytes.map(|b| String::from_utf8_lossy(&b).into_owned());
+    let lockfile = text(split_off_section(&mut output, "lockfile"));
+    let env = text(split_off_section(&mut output, "env"));
+    let cargo_args = text(split_off_section(&mut output, "args"));
+    let artifacts = render::Artifacts {
+        args: cargo_args,
+        env,
+        lockfile,
+    };
+    let mut log =
+        provenance::header(host, job.budget, started, artifacts.env.as_deref()).into_bytes();
+    log.extend(output);
+    let output = log;
+
+    // Render HTML for the stderr/stdout we captured
+    let rendered = render::render_crate(krate, args.tool, &output, &artifacts);
+    let script = repro::script(args.tool, krate, &output, &artifacts);
+
+    // Upload both, and what they depend on
+    client.upload_raw(krate, output).await.unwrap();
+    if let Some(lockfile) = artifacts.lockfile {
+        client
+            .upload_lockfile(krate, lockfile.into_bytes())
+            .await
+            .unwrap();
+    }
+    if let Some(cargo_args) = artifacts.args {
+        client
+            .upload_args(krate, cargo_args.into_bytes())
+            .await
+            .unwrap();
+    }
+    if let Some(env) = artifacts.env {
+        client.upload_env(krate, env.into_bytes()).await.unwrap();
+    }
+    if let Some(script) = script {
+        client
+            .upload_repro(krate, script.into_bytes())
+            .await
+            .unwrap();
+    }
+    client
+        .upload_html(krate, rendered.into_bytes())
+        .await
+        .unwrap();
+}
+
+/// Keeps the start and end of a log that is too big to store, and drops the middle. The end is
+/// where the tests finish and where the worker reports what the result depends on, and the start
+/// is usually where things started to go wrong.
+struct CappedOutput {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    /// How much of the start and of the end to keep
+    half: usize,
+    dropped: usize,
+    /// How much was pushed, including what was dropped
+    total: usize,
+}
+
+impl CappedOutput {
+    fn new(cap: usize) -> Self {
+        Self {
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            half: cap / 2,
+            dropped: 0,
+            total: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.total += bytes.len();
+        let to_head = self.half.saturating_sub(self.head.len()).min(bytes.len());
+        self.head.extend_from_slice(&bytes[..to_head]);
+        self.tail.extend(&bytes[to_head..]);
+        let excess = self.tail.len().saturating_sub(self.half);
+        self.tail.drain(..excess);
+        self.dropped += excess;
+    }
+
+    /// The output with a marker where the middle was dropped, and whether it was.
+    fn finish(mut self) -> (Vec<u8>, bool) {
+        if self.dropped == 0 {
+            self.head.extend(self.tail);
+            return (self.head, false);
+        }
+        // Don't start the tail in the middle of a line
+        let partial = self
+            .tail
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        self.tail.drain(..partial);
+        self.dropped += partial;
+        // Diagnosed by the rules for this message
+        self.head.extend_from_slice(
+            format!(
+                "\ncrater-at-home: truncated {} bytes of output here\n",
+                self.dropped
+            )
+            .as_bytes(),
+        );
+        self.head.extend(self.tail);
+        (self.head, true)
+    }
+}
+
+/// How many runs each crate has caused some problem in, kept in the bucket so that future runs
+/// can put off or skip the crates that keep doing it.
+struct Strikes {
+    key: String,
+    counts: tokio::sync::Mutex<HashMap<String, usize>>,
+}
+
+impl Strikes {
+    async fn download(client: &Client, key: String) -> Result<Self> {
+        let counts = match client.download_if_exists(&key).await? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            key,
+            counts: tokio::sync::Mutex::new(counts),
+        })
+    }
+
+    /// Uploaded right away, so that it is not lost if this run is killed.
+    async fn record(&self, client: &Client, name: &str) {
+        let mut counts = self.counts.lock().await;
+        // Other shards of the run save to the same key, so add to what they have saved
+        let latest = match client.download_if_exists(&self.key).await {
+            Ok(json) => json.and_then(|json| serde_json::from_slice(&json).ok()),
+            Err(e) => {
+                log::warn!("Failed to download {}: {}", self.key, e);
+                None
+            }
+        };
+        add_strike(&mut counts, latest, name);
+        let json = serde_json::to_vec(&*counts).unwrap();
+        if let Err(e) = client.upload(&self.key, &json, "application/json").await {
+            log::warn!("Failed to save {}: {}", self.key, e);
+        }
+    }
+}
+
+/// Adds a strike for `name` to the latest counts that were saved, or to ours if we couldn't get
+/// them.
+fn add_strike(
+    counts: &mut HashMap<String, usize>,
+    latest: Option<HashMap<String, usize>>,
+    name: &str,
+) {
+    if let Some(latest) = latest {
+        *counts = latest;
+    }
+    *counts.entry(name.to_string()).or_default() += 1;
+}
+
+/// Crates are quarantined by version, so that a release that fixes the crash is run again.
+fn quarantine_key(krate: &Crate) -> String {
+    format!("{}@{}", krate.name, krate.version)
+}
+
+/// Crate names from a file with one per line, ignoring `#` comments.
+fn read_skip_list(path: &Path) -> Result<HashSet<String>> {
+    let list = fs::read_to_string(path)?;
+    Ok(list
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Removes the section `docker/run.sh` printed after `-{TEST_END_DELIMITER}-{name}-` from the end
+/// of the output, and returns it.
+fn split_off_section(output: &mut Vec<u8>, name: &str) -> Option<Vec<u8>> {
+    let marker = format!("-{}-{}-\n", *TEST_END_DELIMITER, name).into_bytes();
+    let start = output
+        .windows(marker.len())
+        .rposition(|window| window == marker)?;
+    let section = output.split_off(start)[marker.len()..].to_vec();
+    // The newline before the marker is not part of what came before it
+    if output.ends_with(b"\n") {
+        output.pop();
+    }
+    Some(section)
+}
+
+struct Worker {
+    child: tokio::process::Child,
+    /// The name of the container
+    name: String,
+    memory_gb: usize,
+    cpus: Cpus,
+    /// Whether the container is on the network, which it only is while it downloads a crate, and
+    /// never for runtimes that fetch crates separately
+    connected: bool,
+    sandbox: Sandbox,
+}
+
+impl Worker {
+    /// Changes the memory limit of the container for the next crate.
+    async fn set_memory(&mut self, memory_gb: usize) {
+        if memory_gb == self.memory_gb {
+            return;
+        }
+        if self.sandbox.set_memory(&self.name, memory_gb).await {
+            self.memory_gb = memory_gb;
+        } else {
+            log::warn!(
+                "Failed to change the memory limit of {} to {}g",
+                self.name,
+                memory_gb
+            );
+        }
+    }
+
+    /// Changes the CPUs the container is pinned to for the next crate.
+    async fn set_cpus(&mut self, cpus: &Cpus) {
+        if *cpus == self.cpus {
+            return;
+        }
+        if self.sandbox.set_cpus(&self.name, cpus).await {
+            self.cpus = cpus.clone();
+        } else {
+            log::warn!("Failed to pin {} to CPUs {}", self.name, cpus);
+        }
+    }
+
+    /// Connects the container to the network or disconnects it, and returns whether that worked.
+    async fn set_network(&mut self, connected: bool) -> bool {
+        if connected != self.connected && self.sandbox.set_network(&self.name, connected).await {
+            self.connected = connected;
+        }
+        self.connected == connected
+    }
+
+    /// Why the worker exited, for a worker whose output has ended.
+    async fn exit_status(&mut self) -> String {
+        match tokio::time::timeout(Duration::from_secs(10), self.child.wait()).await {
+            Ok(Ok(status)) => status.to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => {
+                // It closed its output without exiting, so it is no use to us anyway
+                self.kill().await;
+                String::from("its output closed")
+            }
+        }
+    }
+
+    async fn kill(&mut self) {
+        if !self.sandbox.kill(&self.name).await {
+            log::warn!("Failed to kill container {}", self.name);
+        }
+        let _ = self.child.kill().await;
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.sandbox.clean_up(&self.name);
+    }
+}
+
+/// What `docker/run.sh` needs to know.
+fn worker_env(args: &Args) -> [(&'static str, String); 3] {
+    [
+        ("TEST_END_DELIMITER", TEST_END_DELIMITER.to_string()),
+        ("TOOL", args.tool.to_string()),
+        ("TARGET", args.target.clone()),
+    ]
+}
+
+fn spawn_worker(args: &Args, cpus: &Cpus) -> Worker {
+    let name = format!("crater-at-home-{}", Uuid::new_v4());
+    let env = worker_env(args);
+    let spec = Spec {
+        name: &name,
+        cpus,
+        memory_gb: args.memory_limit_gb,
+        env: &env,
+    };
+    Worker {
+        child: args.sandbox.spawn(&spec).unwrap(),
+        name,
+        memory_gb: args.memory_limit_gb,
+        cpus: cpus.clone(),
+        connected: !args.sandbox.fetches_separately(),
+        sandbox: args.sandbox.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attempt(ending: Ending, truncated: bool) -> Attempt {
+        Attempt {
+            output: Vec::new(),
+            ending,
+            truncated,
+        }
+    }
+
+    #[test]
+    fn only_output_counts_as_oversized() {
+        assert!(attempt(Ending::Exceeded(Limit::Output(256)), false).oversized());
+        assert!(attempt(Ending::Finished, true).oversized());
+        assert!(!attempt(Ending::Exceeded(Limit::Time(3600)), false).oversized());
+        assert!(!attempt(Ending::Finished, false).oversized());
+        assert!(!attempt(Ending::Crashed, false).oversized());
+    }
+
+    #[test]
+    fn limits_are_written_as_the_rules_expect() {
+        let kind = |limit: Limit| {
+            let log = format!("test foo ... \ncrater-at-home: killed after exceeding {limit}\n");
+            match diagnose_status(rules(), Tool::Miri, log.as_bytes()) {
+                crate::Status::Error(kind) => kind,
+                _ => panic!("{limit} was not diagnosed as an error"),
+            }
+        };
+        assert_eq!(kind(Limit::Time(60)), "Hung");
+        assert_eq!(kind(Limit::Output(256)), "Log bomb");
+    }
+
+    #[tokio::test]
+    async fn second_signal_interrupts_waits() {
+        let shutdown = Arc::new(Shutdown::default());
+        let wait = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(3600)) => false,
+                    _ = shutdown.aborted() => true,
+                }
+            }
+        });
+        shutdown.signal();
+        assert!(shutdown.draining());
+        tokio::task::yield_now().await;
+        assert!(!wait.is_finished());
+        shutdown.signal();
+        let stopped = tokio::time::timeout(Duration::from_secs(5), wait).await;
+        assert!(stopped.unwrap().unwrap());
+    }
+
+    #[test]
+    fn strikes_add_to_what_was_saved() {
+        let mut counts = HashMap::from([(String::from("a"), 1)]);
+        add_strike(&mut counts, None, "a");
+        add_strike(&mut counts, None, "b");
+        assert_eq!(
+            counts,
+            HashMap::from([(String::from("a"), 2), (String::from("b"), 1)])
+        );
+
+        // Another shard has saved since, so its counts win
+        let latest = HashMap::from([(String::from("a"), 5), (String::from("c"), 1)]);
+        add_strike(&mut counts, Some(latest), "c");
+        assert_eq!(
+            counts,
+            HashMap::from([(String::from("a"), 5), (String::from("c"), 2)])
+        );
+    }
+
+    #[test]
+    fn small_output_is_kept() {
+        let mut output = CappedOutput::new(100);
+        output.push(b"one\n");
+        output.push(b"two\n");
+        assert_eq!(output.total, 8);
+        assert_eq!(output.finish(), (b"one\ntwo\n".to_vec(), false));
+    }
+
+    #[test]
+    fn big_output_keeps_the_start_and_the_end() {
+        let mut output = CappedOutput::new(20);
+        for line in 0..10 {
+            output.push(format!("line {line}\n").as_bytes());
+        }
+        assert_eq!(output.total, 70);
+        let (kept, truncated) = output.finish();
+        assert!(truncated);
+        // The first 10 bytes, and the whole lines in the last 10
+        let expected = "line 0\nlin\n\
+            crater-at-home: truncated 53 bytes of output here\n\
+            line 9\n";
+        assert_eq!(String::from_utf8(kept).unwrap(), expected);
+    }
 }
@@ -0,0 +1,442 @@
+//! The programs that can isolate workers from the host: Docker, rootless Podman, or bubblewrap
+//! for hosts where neither is allowed.
+
+use crate::cpus::Cpus;
+use color_eyre::eyre::{ensure, eyre, Result};
+use std::{
+    fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// What `build_image` tags the image as
+pub const IMAGE: &str = "crater-at-home:latest";
+
+/// Everything the image has on its PATH.
+const PATH: &str = "/root/.cargo/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(
+    clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+pub enum Runtime {
+    Docker,
+    Podman,
+    /// Runs the image's root filesystem with bwrap, in a systemd scope for the memory limit. Its
+    /// workers never have the network; crates are fetched for them in a bwrap of their own.
+    Bubblewrap,
+}
+
+#[derive(clap::Args, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Sandbox {
+    /// What to isolate the workers with
+    #[clap(long, value_enum, default_value_t = Runtime::Docker)]
+    runtime: Runtime,
+
+    /// For bubblewrap, the root filesystem of the image, as made by
+    /// `docker export $(docker create crater-at-home) | tar x -C rootfs` on any host that has
+    /// Docker. `docker/run.sh` and `docker/nextest.toml` are taken from this checkout instead.
+    #[clap(long, required_if_eq("runtime", "bubblewrap"))]
+    rootfs: Option<PathBuf>,
+
+    /// For bubblewrap, where the directories the crates are built in go
+    #[clap(long, default_value = "/var/tmp")]
+    scratch_dir: PathBuf,
+}
+
+/// What a worker is allowed, and what `docker/run.sh` needs to know.
+pub struct Spec<'a> {
+    pub name: &'a str,
+    pub cpus: &'a Cpus,
+    pub memory_gb: usize,
+    pub env: &'a [(&'a str, String)],
+}
+
+impl Sandbox {
+    /// Gets the image ready and returns what identifies it in the provenance of results.
+    pub fn build_image(&self) -> Result<String> {
+        let program = match self.runtime {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+            Runtime::Bubblewrap => {
+                let rootfs = self.rootfs()?;
+                ensure!(
+                    rootfs.join("root/.cargo/bin").is_dir(),
+                    "{} does not look like the root filesystem of the image",
+                    rootfs.display()
+                );
+                // bwrap can't make the mount points in a read-only root
+                for dir in ["build", "tmp", "root/.cargo/registry", "root/.cache"] {
+                    fs::create_dir_all(rootfs.join(dir))?;
+                }
+                return Ok(format!("rootfs {}", rootfs.display()));
+            }
+        };
+        let dockerfile = if std::env::var_os("CI").is_some() {
+            "docker/Dockerfile.ci"
+        } else {
+            "docker/Dockerfile"
+        };
+        let status = Command::new(program)
+            .args(["build", "-t", "crater-at-home", "-f", dockerfile, "docker/"])
+            .status()?;
+        ensure!(status.success(), "{} image build failed!", program);
+        let image = Command::new(program)
+            .args(["image", "inspect", "--format={{.Id}}", "crater-at-home"])
+            .output()?;
+        Ok(String::from_utf8_lossy(&image.stdout).trim().to_string())
+    }
+
+    /// Runs `program` from the image once, with the network and nothing else from the host.
+    pub fn command(&self, program: &str, args: &[&str]) -> Result<Command> {
+        let mut cmd = match self.runtime {
+            Runtime::Docker | Runtime::Podman => {
+                let mut cmd = Command::new(self.client());
+                cmd.args(["run", "--rm", &format!("--entrypoint={program}"), IMAGE]);
+                cmd
+            }
+            Runtime::Bubblewrap => {
+                let mut cmd = Command::new("bwrap");
+                cmd.args(self.bwrap_root(true)?)
+                    .args(["--tmpfs", "/tmp"])
+                    .arg(program);
+                cmd
+            }
+        };
+        cmd.args(args);
+        Ok(cmd)
+    }
+
+    /// Starts a container that runs `docker/run.sh` on the crates written to its stdin.
+    pub fn spawn(&self, spec: &Spec) -> Result<tokio::process::Child> {
+        let mut cmd = match self.runtime {
+            Runtime::Docker | Runtime::Podman => {
+                let mut cmd = Command::new(self.client());
+                cmd.args([
+                    "run",
+                    "--rm",
+                    "--interactive",
+                    &format!("--name={}", spec.name),
+                    // Named, so that the worker can be taken off it while it builds and tests
+                    &format!("--network={}", self.network()),
+                    // Pin the build to its CPUs; this also ensures that anything doing
+                    // make -j $(nproc)
+                    // will not spawn processes appropriate for the host.
+                    &format!("--cpuset-cpus={}", spec.cpus),
+                    // We set up our filesystem as read-only, but with 3 exceptions
+                    "--read-only",
+                    // The directory we are building in (not just its target dir!) is all writable
+                    "--volume=/build",
+                    // rustdoc tries to write to and executes files in /tmp, odd move but whatever
+                    "--tmpfs=/tmp:exec",
+                    // The default cargo registry location; we download dependences in the sandbox
+                    "--tmpfs=/root/.cargo/registry",
+                    // cargo-miri builds a sysroot under /root/.cache, so why not make it all writeable
+                    "--tmpfs=/root/.cache:exec",
+                ]);
+                // And its memory to their nodes
+                if let Some(nodes) = spec.cpus.nodes() {
+                    cmd.arg(format!("--cpuset-mems={nodes}"));
+                }
+                for (key, value) in spec.env {
+                    cmd.arg(format!("--env={key}={value}"));
+                }
+                cmd.args([
+                    // Enforce the memory limit
+                    &format!("--memory={}g", spec.memory_gb),
+                    // Setting --memory-swap to the same value turns off swap
+                    &format!("--memory-swap={}g", spec.memory_gb),
+                    IMAGE,
+                ]);
+                cmd
+            }
+            Runtime::Bubblewrap => {
+                // The scope is the cgroup that the memory limit is set on
+                let mut cmd = Command::new("systemd-run");
+                cmd.args([
+                    "--user",
+                    "--scope",
+                    "--quiet",
+                    &format!("--unit={}", spec.name),
+                    &format!("--property=MemoryMax={}G", spec.memory_gb),
+                    "--property=MemorySwapMax=0",
+                ]);
+                if let Some(nodes) = spec.cpus.nodes() {
+                    cmd.arg(format!("--property=AllowedMemoryNodes={nodes}"));
+                }
+                cmd.args([
+                    // Pinned like the containers are
+                    "taskset",
+                    &format!("--cpu-list={}", spec.cpus),
+                    "bwrap",
+                ]);
+                cmd.args(self.bwrap_worker(spec, false)?);
+                cmd.args(["/usr/bin/nice", "-n19", "bash", "/root/run.sh"]);
+                cmd
+            }
+        };
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Ctrl-C goes to the whole process group, and we decide what happens to the workers
+            .process_group(0);
+        Ok(tokio::process::Command::from(cmd).spawn()?)
+    }
+
+    /// Whether workers can never have the network, so `fetch` downloads crates for them. A bwrap
+    /// has the network or not for its whole life.
+    pub fn fetches_separately(&self) -> bool {
+        self.runtime == Runtime::Bubblewrap
+    }
+
+    /// Downloads a crate and its dependencies for a worker that `fetches_separately`, in a
+    /// sandbox of its own that has the network and shares the worker's scratch directories. It
+    /// prints what `docker/run.sh` prints for a crate before it is taken off the network, and
+    /// then the flags to build the crate with in a section of their own.
+    pub fn fetch(
+        &self,
+        spec: &Spec,
+        krate: &str,
+        timeout_secs: u64,
+    ) -> Result<tokio::process::Command> {
+        ensure!(
+            self.fetches_separately(),
+            "{:?} workers download crates themselves",
+            self.runtime
+        );
+        let mut cmd = Command::new("taskset");
+        cmd.arg(format!("--cpu-list={}", spec.cpus))
+            .arg("bwrap")
+            .args(self.bwrap_worker(spec, true)?)
+            .args(["/usr/bin/nice", "-n19", "bash", "/root/run.sh", "fetch"])
+            .arg(krate)
+            .arg(timeout_secs.to_string());
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0);
+        let mut cmd = tokio::process::Command::from(cmd);
+        cmd.kill_on_drop(true);
+        Ok(cmd)
+    }
+
+    /// Changes the memory limit of a running worker, and returns whether that worked.
+    pub async fn set_memory(&self, name: &str, memory_gb: usize) -> bool {
+        let mut cmd = match self.runtime {
+            Runtime::Docker | Runtime::Podman => {
+                let mut cmd = tokio::process::Command::new(self.client());
+                cmd.args([
+                    "update",
+                    &format!("--memory={memory_gb}g"),
+                    // Setting --memory-swap to the same value turns off swap
+                    &format!("--memory-swap={memory_gb}g"),
+                    name,
+                ]);
+                cmd
+            }
+            Runtime::Bubblewrap => {
+                let mut cmd = tokio::process::Command::new("systemctl");
+                cmd.args([
+                    "--user",
+                    "set-property",
+                    "--runtime",
+                    &format!("{name}.scope"),
+                    &format!("MemoryMax={memory_gb}G"),
+                ]);
+                cmd
+            }
+        };
+        let status = cmd.stdout(Stdio::null()).status().await;
+        status.is_ok_and(|status| status.success())
+    }
+
+    /// Changes the CPUs a running worker is pinned to, and returns whether that worked.
+    pub async fn set_cpus(&self, name: &str, cpus: &Cpus) -> bool {
+        match self.runtime {
+            Runtime::Docker | Runtime::Podman => {
+                let status = tokio::process::Command::new(self.client())
+                    .args(["update", &format!("--cpuset-cpus={cpus}"), name])
+                    .stdout(Stdio::null())
+                    .status()
+                    .await;
+                status.is_ok_and(|status| status.success())
+            }
+            // The pinning is taskset's, which is inherited, so it is changed for everything in
+            // the scope; what the worker starts next inherits it from run.sh.
+            Runtime::Bubblewrap => {
+                let Ok(output) = tokio::process::Command::new("systemctl")
+                    .args([
+                        "--user",
+                        "show",
+                        "--property=ControlGroup",
+                        "--value",
+                        &format!("{name}.scope"),
+                    ])
+                    .output()
+                    .await
+                else {
+                    return false;
+                };
+                let cgroup = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                let Ok(procs) = fs::read_to_string(format!("/sys/fs/cgroup{cgroup}/cgroup.procs"))
+                else {
+                    return false;
+                };
+                let mut changed = true;
+                for pid in procs.lines() {
+                    let status = tokio::process::Command::new("taskset")
+                        .args(["--all-tasks", "--pid", "--cpu-list", &cpus.to_string(), pid])
+                        .stdout(Stdio::null())
+                        .status()
+                        .await;
+                    changed &= status.is_ok_and(|status| status.success());
+                }
+                changed
+            }
+        }
+    }
+
+    /// Connects a running worker to the network or disconnects it, and returns whether that
+    /// worked.
+    pub async fn set_network(&self, name: &str, connected: bool) -> bool {
+        if self.fetches_separately() {
+            // They never have it
+            return !connected;
+        }
+        let action = if connected { "connect" } else { "disconnect" };
+        let status = tokio::process::Command::new(self.client())
+            .args(["network", action, self.network(), name])
+            .stdout(Stdio::null())
+            .status()
+            .await;
+        status.is_ok_and(|status| status.success())
+    }
+
+    /// Stops a worker and everything it started, and returns whether that worked. Killing the
+    /// process we started is not enough, since the container runtimes run it elsewhere.
+    pub async fn kill(&self, name: &str) -> bool {
+        let mut cmd = match self.runtime {
+            Runtime::Docker | Runtime::Podman => {
+                let mut cmd = tokio::process::Command::new(self.client());
+                cmd.args(["kill", name]);
+                cmd
+            }
+            Runtime::Bubblewrap => {
+                let mut cmd = tokio::process::Command::new("systemctl");
+                cmd.args([
+                    "--user",
+                    "kill",
+                    "--signal=SIGKILL",
+                    &format!("{name}.scope"),
+                ]);
+                cmd
+            }
+        };
+        let status = cmd
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        status.is_ok_and(|status| status.success())
+    }
+
+    /// Deletes what a worker that has exited leaves on the host.
+    pub fn clean_up(&self, name: &str) {
+        if self.runtime == Runtime::Bubblewrap {
+            let _ = fs::remove_dir_all(self.scratch(name));
+        }
+    }
+
+    fn client(&self) -> &'static str {
+        match self.runtime {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+            Runtime::Bubblewrap => unreachable!("bubblewrap has no client"),
+        }
+    }
+
+    /// The network the runtime puts containers on when it isn't told otherwise
+    fn network(&self) -> &'static str {
+        match self.runtime {
+            Runtime::Docker => "bridge",
+            Runtime::Podman => "podman",
+            Runtime::Bubblewrap => unreachable!("bubblewrap has no networks"),
+        }
+    }
+
+    fn rootfs(&self) -> Result<&Path> {
+        self.rootfs
+            .as_deref()
+            .ok_or_else(|| eyre!("--runtime=bubblewrap needs --rootfs"))
+    }
+
+    fn scratch(&self, name: &str) -> PathBuf {
+        self.scratch_dir.join(name)
+    }
+
+    /// The arguments to bwrap for a worker, or for fetching crates for it with the network. Both
+    /// see the same scratch directories on the host, so the worker builds what was fetched.
+    fn bwrap_worker(&self, spec: &Spec, network: bool) -> Result<Vec<String>> {
+        let mut args = self.bwrap_root(network)?;
+        let scratch = self.scratch(spec.name);
+        // Like the volume and tmpfs mounts of the containers, which are all executable
+        for (dir, mount) in [
+            ("build", "/build"),
+            ("registry", "/root/.cargo/registry"),
+            ("cache", "/root/.cache"),
+        ] {
+            let dir = scratch.join(dir);
+            fs::create_dir_all(&dir)?;
+            args.extend(["--bind".into(), dir.display().to_string(), mount.into()]);
+        }
+        args.extend(["--tmpfs", "/tmp"].map(String::from));
+        for (file, mount) in [
+            ("docker/run.sh", "/root/run.sh"),
+            ("docker/nextest.toml", "/root/.cargo/nextest.toml"),
+        ] {
+            let file = fs::canonicalize(file)?.display().to_string();
+            args.extend(["--ro-bind".into(), file, mount.into()]);
+        }
+        for (key, value) in spec.env {
+            args.extend(["--setenv".into(), key.to_string(), value.clone()]);
+        }
+        args.extend(["--setenv", "FETCH_SEPARATELY", "1"].map(String::from));
+        Ok(args)
+    }
+
+    /// The arguments to bwrap for a read-only root like `docker run --read-only` gives, in new
+    /// namespaces, with the host's network only if `network` is set.
+    fn bwrap_root(&self, network: bool) -> Result<Vec<String>> {
+        let rootfs = self.rootfs()?.display().to_string();
+        let mut args = [
+            "--ro-bind",
+            &rootfs,
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "--unshare-all",
+            "--die-with-parent",
+            "--uid",
+            "0",
+            "--gid",
+            "0",
+            "--chdir",
+            "/",
+            "--clearenv",
+            "--setenv",
+            "PATH",
+            PATH,
+            "--setenv",
+            "HOME",
+            "/root",
+        ]
+        .map(String::from)
+        .to_vec();
+        if network {
+            args.push("--share-net".into());
+        }
+        Ok(args)
+    }
+}
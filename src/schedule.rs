@@ -0,0 +1,318 @@
+//! How long crates take to run, so that the slowest ones can be started first instead of holding
+//! up the end of a run, and so that we can tell when a run will finish.
+
+use crate::{client::Client, Crate, Tool};
+use color_eyre::eyre::{ensure, eyre, Result};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Mutex};
+
+/// What we guess for a crate when no crate has been timed yet.
+const DEFAULT_ESTIMATE_SECS: u64 = 5 * 60;
+
+/// Saving the durations is not cheap, so it is done after this many crates finish.
+const SAVE_EVERY: usize = 100;
+
+/// The wall time of the most recent run of each crate, by name, in seconds.
+pub struct Durations {
+    key: String,
+    state: Mutex<DurationsState>,
+    /// What we guess for crates that have never been timed
+    fallback_secs: u64,
+}
+
+struct DurationsState {
+    secs: HashMap<String, u64>,
+    /// The crates timed by this run
+    recorded: HashMap<String, u64>,
+    unsaved: usize,
+}
+
+impl Durations {
+    pub async fn download(client: &Client, key: String) -> Result<Self> {
+        let secs: HashMap<String, u64> = match client.download_if_exists(&key).await? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => HashMap::new(),
+        };
+        Ok(Self::new(key, secs))
+    }
+
+    fn new(key: String, secs: HashMap<String, u64>) -> Self {
+        let mut all = secs.values().copied().collect::<Vec<_>>();
+        all.sort_unstable();
+        let fallback_secs = all
+            .get(all.len() / 2)
+            .copied()
+            .unwrap_or(DEFAULT_ESTIMATE_SECS);
+        Self {
+            key,
+            state: Mutex::new(DurationsState {
+                secs,
+                recorded: HashMap::new(),
+                unsaved: 0,
+            }),
+            fallback_secs,
+        }
+    }
+
+    /// How long we expect this crate to take, in seconds.
+    pub fn estimate(&self, krate: &Crate) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .secs
+            .get(&krate.name)
+            .copied()
+            .unwrap_or(self.fallback_secs)
+    }
+
+    pub async fn record(&self, client: &Client, krate: &Crate, secs: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.secs.insert(krate.name.clone(), secs);
+            state.recorded.insert(krate.name.clone(), secs);
+            state.unsaved += 1;
+            if state.unsaved < SAVE_EVERY {
+                return;
+            }
+            state.unsaved = 0;
+        }
+        self.upload(client).await;
+    }
+
+    /// Uploads whatever has not been saved yet.
+    pub async fn save(&self, client: &Client) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.unsaved == 0 {
+                return;
+            }
+            state.unsaved = 0;
+        }
+        self.upload(client).await;
+    }
+
+    async fn upload(&self, client: &Client) {
+        // Other shards of the run save to the same key, so add to what they have saved
+        let latest = match client.download_if_exists(&self.key).await {
+            Ok(json) => json.and_then(|json| serde_json::from_slice(&json).ok()),
+            Err(e) => {
+                log::warn!("Failed to download {}: {}", self.key, e);
+                None
+            }
+        };
+        let json = {
+            let state = self.state.lock().unwrap();
+            let mut secs: HashMap<String, u64> = latest.unwrap_or_else(|| state.secs.clone());
+            secs.extend(state.recorded.clone());
+            serde_json::to_vec(&secs).unwrap()
+        };
+        if let Err(e) = client.upload(&self.key, &json, "application/json").await {
+            log::warn!("Failed to save {}: {}", self.key, e);
+        }
+    }
+}
+
+/// One of `count` disjoint parts of a run, written `index/count` with `index` counting from 1, so
+/// that several machines can split a run between them without talking to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Shard {
+    index: usize,
+    count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| eyre!("Expected a shard like 1/4, got {}", s))?;
+        let shard = Shard {
+            index: index.parse()?,
+            count: count.parse()?,
+        };
+        ensure!(
+            (1..=shard.count).contains(&shard.index),
+            "Shard {} is not one of 1 to {}",
+            shard.index,
+            shard.count
+        );
+        Ok(shard)
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    /// The durations every shard of a run picks its crates by: a copy of the durations as they
+    /// were when the first shard started on this UTC day. The durations the shards save as they
+    /// go would otherwise change who runs what for the shards that start later.
+    pub async fn pinned_durations(client: &Client, tool: Tool) -> Result<Durations> {
+        let key = tool.shard_durations_path(time::OffsetDateTime::now_utc().date());
+        if client.download_if_exists(&key).await?.is_none() {
+            let current = client
+                .download_if_exists(&tool.durations_path())
+                .await?
+                .unwrap_or_else(|| b"{}".to_vec());
+            client.upload(&key, &current, "application/json").await?;
+        }
+        Durations::download(client, key).await
+    }
+
+    /// This shard's part of `crates`. Each crate goes to the shard with the least estimated work
+    /// so far, taking the slowest first and breaking ties by a hash of the name, so the shards
+    /// take about as long as each other. The shards agree on who runs what as long as they see
+    /// the same crates and `durations`, which should come from `pinned_durations`.
+    pub fn select(self, crates: Vec<Crate>, durations: &Durations) -> Vec<Crate> {
+        let mut crates = crates
+            .into_iter()
+            .map(|krate| (durations.estimate(&krate), stable_hash(&krate.name), krate))
+            .collect::<Vec<_>>();
+        crates.sort_by(|(a_cost, a_hash, a), (b_cost, b_hash, b)| {
+            b_cost
+                .cmp(a_cost)
+                .then(a_hash.cmp(b_hash))
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.version.cmp(&b.version))
+        });
+        let mut loads = vec![0; self.count];
+        let mut mine = Vec::new();
+        for (cost, _, krate) in crates {
+            let (shard, load) = loads
+                .iter_mut()
+                .enumerate()
+                .min_by_key(|(_, load)| **load)
+                .unwrap();
+            *load += cost;
+            if shard + 1 == self.index {
+                mine.push(krate);
+            }
+        }
+        mine
+    }
+}
+
+/// FNV-1a, which unlike the hasher in std is the same on every machine and Rust version.
+fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// How much work is left in a run, to estimate when it will be done.
+pub struct Progress {
+    jobs: usize,
+    state: Mutex<(usize, u64)>,
+}
+
+impl Progress {
+    pub fn new(jobs: usize) -> Self {
+        Self {
+            jobs,
+            state: Mutex::new((0, 0)),
+        }
+    }
+
+    /// A crate was added to the queue.
+    pub fn queued(&self, estimate_secs: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        state.1 += estimate_secs;
+    }
+
+    /// A crate was taken off the queue.
+    pub fn started(&self, estimate_secs: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = state.0.saturating_sub(1);
+        state.1 = state.1.saturating_sub(estimate_secs);
+    }
+
+    /// Like "1234 crates left, done in about 5h 6m".
+    pub fn eta(&self) -> String {
+        let (crates, secs) = *self.state.lock().unwrap();
+        format!(
+            "{} crates left, done in about {}",
+            crates,
+            format_secs(secs / self.jobs.max(1) as u64)
+        )
+    }
+}
+
+pub fn format_secs(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3600, secs % 3600 / 60);
+    if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {}s", secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Status, Version};
+
+    #[test]
+    fn shards_split_the_crates_between_them() {
+        let crates = (0..1000)
+            .map(|i| Crate {
+                name: format!("crate-{i}"),
+                version: Version::parse("1.0.0"),
+                recent_downloads: None,
+                status: Status::Unknown,
+            })
+            .collect::<Vec<_>>();
+        // A few slow crates and many quick ones, and some that have never been timed
+        let secs = crates
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 10 != 0)
+            .map(|(i, krate)| {
+                (
+                    krate.name.clone(),
+                    if i % 97 == 0 { 3600 } else { 60 + i as u64 },
+                )
+            })
+            .collect();
+        let durations = Durations::new(String::new(), secs);
+
+        let count = 3;
+        let mut shards_of = HashMap::<String, Vec<usize>>::new();
+        let mut loads = Vec::new();
+        for index in 1..=count {
+            let shard = format!("{index}/{count}").parse::<Shard>().unwrap();
+            let mine = shard.select(crates.clone(), &durations);
+            loads.push(
+                mine.iter()
+                    .map(|krate| durations.estimate(krate))
+                    .sum::<u64>(),
+            );
+            for krate in mine {
+                shards_of.entry(krate.name).or_default().push(index);
+            }
+        }
+        for krate in &crates {
+            assert_eq!(
+                shards_of.get(&krate.name).map(Vec::len),
+                Some(1),
+                "{} is in shards {:?}",
+                krate.name,
+                shards_of.get(&krate.name)
+            );
+        }
+        // No further apart than the slowest crate
+        let spread = loads.iter().max().unwrap() - loads.iter().min().unwrap();
+        assert!(spread <= 3600, "the shards have {loads:?} seconds of work");
+    }
+
+    #[test]
+    fn shard_bounds() {
+        assert!("0/3".parse::<Shard>().is_err());
+        assert!("4/3".parse::<Shard>().is_err());
+        assert_eq!("3/3".parse::<Shard>().unwrap().to_string(), "3/3");
+    }
+}
@@ -1,10 +1,10 @@
 use crate::Version;
-use crate::{client::Client, db_dump, render, Crate, Tool};
+use crate::{client::Client, db_dump, deps, feed, render, Crate, Tool};
 use aws_smithy_types_convert::date_time::DateTimeExt;
 use clap::Parser;
 use color_eyre::{Report, Result};
 use std::collections::hash_map::Entry;
-use std::{collections::HashMap, fmt::Write, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 use tokio::{sync::Mutex, sync::Semaphore, task::JoinSet};
 
 #[derive(Parser)]
@@ -14,6 +14,10 @@ pub struct Args {
 
     #[clap(long)]
     bucket: String,
+
+    /// The URL the bucket is served from, used for links in the Atom feeds
+    #[clap(long)]
+    base_url: Option<String>,
 }
 
 #[tokio::main]
@@ -27,47 +31,108 @@ pub async fn run(args: Args) -> Result<()> {
     client
         .upload(
             &format!("{}/403", args.tool),
-            ERROR_PAGE.as_bytes(),
+            render::ERROR_PAGE.as_bytes(),
             "text/html",
         )
         .await?;
 
+    let name_to_downloads = refresh_db(&client, time::Duration::hours(24)).await?;
+
+    log::info!("Downloading, rendering, and uploading rendered HTML for all crates");
+    let (crates, history) = sync_all_html(client.clone()).await?;
+
+    let base_url = args
+        .base_url
+        .unwrap_or_else(|| default_base_url(&args.bucket));
+    publish(&client, crates, &history, &name_to_downloads, &base_url).await
+}
+
+/// Where the bucket is served from, unless told otherwise.
+pub fn default_base_url(bucket: &str) -> String {
+    format!("https://{}.s3.amazonaws.com", bucket)
+}
+
+/// Downloads the crates.io database again if our copy is older than `max_age`, and returns the
+/// recent downloads of each crate.
+pub async fn refresh_db(
+    client: &Client,
+    max_age: time::Duration,
+) -> Result<HashMap<String, Option<u64>>> {
     let should_refresh_db = client
         .list_db()
         .await?
         .map(|db| {
             let now = time::OffsetDateTime::now_utc();
             let modified = db.last_modified.unwrap().to_time().unwrap();
-            (now - modified).whole_hours() > 24
+            now - modified > max_age
         })
         .unwrap_or(true);
 
-    let name_to_downloads = if should_refresh_db {
-        log::info!("Updating the cached crates.io database dump");
-        let crates = db_dump::download()?;
-        let mut name_to_downloads = HashMap::new();
-        let mut versions = Vec::new();
-        for krate in crates.iter() {
-            name_to_downloads.insert(krate.name.clone(), krate.recent_downloads);
-            versions.push((krate.name.clone(), krate.version.to_string()));
-        }
+    if !should_refresh_db {
+        return client.get_crate_downloads().await;
+    }
+    log::info!("Updating the cached crates.io database dump");
+    let crates = tokio::task::spawn_blocking(db_dump::download).await??;
+    let mut name_to_downloads = HashMap::new();
+    let mut versions = Vec::new();
+    for krate in crates.iter() {
+        name_to_downloads.insert(krate.name.clone(), krate.recent_downloads);
+        versions.push((krate.name.clone(), krate.version.to_string()));
+    }
 
-        let serialized = serde_json::to_string(&versions).unwrap();
-        client
-            .upload("crates.json", serialized.as_bytes(), "application/json")
-            .await?;
-        let serialized = serde_json::to_string(&name_to_downloads).unwrap();
-        client
-            .upload("downloads.json", serialized.as_bytes(), "application/json")
-            .await?;
-        name_to_downloads
-    } else {
-        client.get_crate_downloads().await?
+    let serialized = serde_json::to_string(&versions).unwrap();
+    client
+        .upload("crates.json", serialized.as_bytes(), "application/json")
+        .await?;
+    let serialized = serde_json::to_string(&name_to_downloads).unwrap();
+    client
+        .upload("downloads.json", serialized.as_bytes(), "application/json")
+        .await?;
+    Ok(name_to_downloads)
+}
+
+/// Renders the crates that finished within `since`, and updates everything that lists crates,
+/// without rendering the rest again.
+pub async fn sync_recent(client: Arc<Client>, since: time::Duration, base_url: &str) -> Result<()> {
+    let tool = client.tool();
+    sync_landing_page(&client).await?;
+
+    let recent = client.list_finished_crates(Some(since)).await?;
+    log::info!(
+        "Re-rendering HTML for {} recently finished crates",
+        recent.len()
+    );
+    let mut history = match client.download_if_exists(&tool.history_path()).await? {
+        Some(json) => serde_json::from_slice(&json)?,
+        None => deps::History::default(),
     };
+    let recent = sync_html(client.clone(), recent, &mut history).await?;
 
-    log::info!("Downloading, rendering, and uploading rendered HTML for all crates");
-    let mut crates = sync_all_html(client.clone()).await?;
+    let mut crates: Vec<Crate> = match client.download_if_exists(&tool.diagnoses_path()).await? {
+        Some(json) => serde_json::from_slice(&json)?,
+        None => Vec::new(),
+    };
+    for krate in recent {
+        match crates.iter_mut().find(|c| c.name == krate.name) {
+            Some(existing) if existing.version > krate.version => {}
+            Some(existing) => *existing = krate,
+            None => crates.push(krate),
+        }
+    }
+
+    let name_to_downloads = client.get_crate_downloads().await?;
+    publish(&client, crates, &history, &name_to_downloads, base_url).await
+}
 
+/// Uploads the pages and feeds that list crates, and what the next `sync_recent` needs.
+async fn publish(
+    client: &Client,
+    mut crates: Vec<Crate>,
+    history: &deps::History,
+    name_to_downloads: &HashMap<String, Option<u64>>,
+    base_url: &str,
+) -> Result<()> {
+    let tool = client.tool();
     // Sort crates by recent downloads, descending
     // Then by version, descending
     crates.sort_by(|crate_a, crate_b| {
@@ -79,25 +144,122 @@ pub async fn run(args: Args) -> Result<()> {
     // Since we sored by version we can dedup by name and be left with only
     // the most recent version of each crate.
     crates.dedup_by(|a, b| a.name == b.name);
+    for krate in &mut crates {
+        krate.recent_downloads = name_to_downloads.get(&krate.name).cloned().flatten();
+    }
+
+    let ub_page = render::render_ub(&crates, history, "")?;
+    client
+        .upload(&format!("{}/ub", tool), ub_page.as_bytes(), "text/html")
+        .await?;
+
+    let clusters_page = render::render_clusters(&crates, "")?;
+    client
+        .upload(
+            &format!("{}/clusters", tool),
+            clusters_page.as_bytes(),
+            "text/html",
+        )
+        .await?;
+
+    let unsupported_page = render::render_unsupported(&crates, "")?;
+    client
+        .upload(
+            &format!("{}/unsupported", tool),
+            unsupported_page.as_bytes(),
+            "text/html",
+        )
+        .await?;
 
-    let ub_page = crate::render::render_ub(&crates)?;
+    let build_failures_page = render::render_build_failures(&crates, "")?;
     client
         .upload(
-            &format!("{}/ub", args.tool),
-            ub_page.as_bytes(),
+            &format!("{}/build-failures", tool),
+            build_failures_page.as_bytes(),
             "text/html",
         )
         .await?;
 
+    let serialized = serde_json::to_string(history)?;
+    client
+        .upload(
+            &tool.history_path(),
+            serialized.as_bytes(),
+            "application/json",
+        )
+        .await?;
+
+    log::info!("Updating the Atom feeds of new UB");
+    sync_feeds(client, &crates, base_url).await?;
+
+    Ok(())
+}
+
+async fn sync_feeds(client: &Client, crates: &[Crate], base_url: &str) -> Result<()> {
+    let tool = client.tool();
+    let previous = client.download_if_exists(&tool.diagnoses_path()).await?;
+
+    // If there is no previous sync to compare against, every crate would look newly-UB.
+    // So the first sync only records the baseline.
+    if let Some(previous) = previous {
+        let previous: Vec<Crate> = serde_json::from_slice(&previous)?;
+        let new = feed::new_ub_entries(&previous, crates)?;
+        log::info!("Found {} crates with new UB", new.len());
+
+        let existing = match client.download_if_exists(&tool.feed_entries_path()).await? {
+            Some(existing) => serde_json::from_slice(&existing)?,
+            None => Vec::new(),
+        };
+        let entries = feed::merge_entries(new, existing);
+        let serialized = serde_json::to_string(&entries)?;
+        client
+            .upload(
+                &tool.feed_entries_path(),
+                serialized.as_bytes(),
+                "application/json",
+            )
+            .await?;
+
+        let rendered = feed::render_feed(tool, base_url, None, &entries)?;
+        client
+            .upload(
+                &tool.feed_path(),
+                rendered.as_bytes(),
+                "application/atom+xml",
+            )
+            .await?;
+        for kind in feed::cause_kinds(&entries) {
+            let rendered = feed::render_feed(tool, base_url, Some(kind), &entries)?;
+            client
+                .upload(
+                    &tool.cause_feed_path(kind),
+                    rendered.as_bytes(),
+                    "application/atom+xml",
+                )
+                .await?;
+        }
+    }
+
+    // Last, because the next sync finds the new UB by comparing against this. If we stop before
+    // here, it finds the same entries again, and merging them replaces these.
+    let serialized = serde_json::to_string(crates)?;
+    client
+        .upload(
+            &tool.diagnoses_path(),
+            serialized.as_bytes(),
+            "application/json",
+        )
+        .await?;
+
     Ok(())
 }
 
-async fn sync_all_html(client: Arc<Client>) -> Result<Vec<Crate>> {
+async fn sync_all_html(client: Arc<Client>) -> Result<(Vec<Crate>, deps::History)> {
     log::info!("Enumerating all finished crates");
     let all = client.list_finished_crates(None).await?;
     log::info!("Re-rendering HTML for {} crates", all.len());
-    let mut tasks = JoinSet::new();
-    let limit = Arc::new(Semaphore::new(256));
+    let mut history = deps::History::default();
+    let crates = sync_html(client.clone(), all, &mut history).await?;
     let all_raw = Arc::new(Mutex::new(tar::Builder::new(xz2::write::XzEncoder::new(
         Vec::new(),
         5,
@@ -106,7 +268,48 @@ async fn sync_all_html(client: Arc<Client>) -> Result<Vec<Crate>> {
         Vec::new(),
         5,
     ))));
-    for krate in all.into_iter().rev() {
+    let raw: Vec<u8> = Arc::into_inner(all_raw)
+        .unwrap()
+        .into_inner()
+        .into_inner()
+        .unwrap()
+        .finish()
+        .unwrap();
+    client
+        .upload(
+            &format!("{}/raw.tar.xz", client.tool()),
+            &raw,
+            "application/octet-stream",
+        )
+        .await?;
+
+    let rendered: Vec<u8> = Arc::into_inner(all_rendered)
+        .unwrap()
+        .into_inner()
+        .into_inner()
+        .unwrap()
+        .finish()
+        .unwrap();
+    client
+        .upload(
+            &format!("{}/html.tar.xz", client.tool()),
+            &rendered,
+            "application/octet-stream",
+        )
+        .await?;
+
+    Ok((crates, history))
+}
+
+/// Renders and uploads the HTML of these crates, and diagnoses them.
+async fn sync_html(
+    client: Arc<Client>,
+    crates: Vec<Crate>,
+    history: &mut deps::History,
+) -> Result<Vec<Crate>> {
+    let mut tasks = JoinSet::new();
+    let limit = Arc::new(Semaphore::new(256));
+    for krate in crates.into_iter().rev() {
         let limit = Arc::clone(&limit);
         let client = Arc::clone(&client);
         //let all_raw = Arc::clone(&all_raw);
@@ -127,7 +330,8 @@ async fn sync_all_html(client: Arc<Client>) -> Result<Vec<Crate>> {
             }
             */
 
-            let rendered = render::render_crate(&krate, &raw);
+            let artifacts = render::Artifacts::download(&client, &krate).await?;
+            let rendered = render::render_crate(&krate, client.tool(), &raw, &artifacts);
             /*
             let mut header = tar::Header::new_gnu();
             if header
@@ -158,47 +362,21 @@ async fn sync_all_html(client: Arc<Client>) -> Result<Vec<Crate>> {
             // Ensure the permit is released once we are done with the client
             drop(permit);
             let mut krate = krate;
-            crate::diagnose(&mut krate, &raw)?;
-            Ok::<_, Report>(krate)
+            crate::diagnose(&mut krate, client.tool(), &raw)?;
+            Ok::<_, Report>((krate, artifacts.parse_lockfile()))
         });
     }
-    let mut crates = Vec::new();
+    let mut results = Vec::new();
     while let Some(task) = tasks.join_next().await {
-        let krate = task??;
-        crates.push(krate);
+        results.push(task??);
     }
+    history.record_all(
+        results
+            .iter()
+            .map(|(krate, lockfile)| (krate, lockfile.as_ref())),
+    );
 
-    let raw: Vec<u8> = Arc::into_inner(all_raw)
-        .unwrap()
-        .into_inner()
-        .into_inner()
-        .unwrap()
-        .finish()
-        .unwrap();
-    client
-        .upload(
-            &format!("{}/raw.tar.xz", client.tool()),
-            &raw,
-            "application/octet-stream",
-        )
-        .await?;
-
-    let rendered: Vec<u8> = Arc::into_inner(all_rendered)
-        .unwrap()
-        .into_inner()
-        .into_inner()
-        .unwrap()
-        .finish()
-        .unwrap();
-    client
-        .upload(
-            &format!("{}/html.tar.xz", client.tool()),
-            &rendered,
-            "application/octet-stream",
-        )
-        .await?;
-
-    Ok(crates)
+    Ok(results.into_iter().map(|(krate, _)| krate).collect())
 }
 
 async fn sync_landing_page(client: &Client) -> Result<()> {
@@ -228,36 +406,8 @@ async fn sync_landing_page(client: &Client) -> Result<()> {
         }
     }
 
-    let mut output = String::from(crate::render::LANDING_PAGE);
-    for (name, version) in max_versions {
-        writeln!(output, "\"{}\":\"{}\",", name, version)?;
-    }
-    output.pop(); // remove the trailing newline
-    output.pop(); // remove the last comma
-    output.push_str("};</script></html>");
-
+    let output = render::render_landing_page(&max_versions, "")?;
     client.upload_landing_page(output.into_bytes()).await?;
 
     Ok(())
 }
-
-static ERROR_PAGE: &str = r#"<!DOCTYPE HTML>
-<html><head><style>
-body {
-    background: #111;
-    color: #eee;
-}
-pre {
-    word-wrap: break-word;
-    white-space: pre-wrap;
-    font-size: 14px;
-    font-size-adjust: none;
-    text-size-adjust: none;
-    -webkit-text-size-adjust: 100%;
-    -moz-text-size-adjust: 100%;
-    -ms-text-size-adjust: 100%;
-}
-</style><title>oops</title></head>
-<body><pre><span style='color:#f55; font-weight:bold'>error</span>: No such file or directory (http error 404)
-
-<span style='color:#f55; font-weight:bold'>error</span>: aborting due to previous error</pre></body></html>"#;
@@ -0,0 +1,136 @@
+//! Running crate versions as they are published, and keeping the site up to date with them.
+
+use crate::{
+    client::Client,
+    queue::Queue,
+    run,
+    sync::{default_base_url, refresh_db, sync_recent},
+    Crate,
+};
+use clap::Parser;
+use color_eyre::eyre::{ensure, Result};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+#[derive(Parser)]
+// The flattened `run::Args` has the same group name as this
+#[group(skip)]
+pub struct Args {
+    /// How often to look for new versions and sync the crates that finished since the last time
+    #[clap(long, default_value_t = 60)]
+    poll_mins: u64,
+
+    /// Download the crates.io database again when our copy is older than this. crates.io
+    /// publishes a new one every day.
+    #[clap(long, default_value_t = 24)]
+    db_max_age_hours: i64,
+
+    /// In between new versions, run the crates `run` would run
+    #[clap(long)]
+    backlog: bool,
+
+    /// The URL the bucket is served from, used for links in the Atom feeds
+    #[clap(long)]
+    base_url: Option<String>,
+
+    #[command(flatten)]
+    run: run::Args,
+}
+
+/// Runs until it is stopped with Ctrl-C or SIGTERM, which work like they do for `run`.
+#[tokio::main]
+pub async fn run(args: Args) -> Result<()> {
+    ensure!(
+        args.run.queue_url.is_none(),
+        "`watch` finds its own crates, so it can't take them from a queue"
+    );
+    let (run_args, resumed) = run::resume(args.run)?;
+    let image = run_args.sandbox.build_image()?;
+    let client = Arc::new(Client::new(run_args.tool, &run_args.bucket).await?);
+    let backlog = match resumed {
+        Some(jobs) => jobs,
+        None if args.backlog => run::build_queue_for(&run_args).await?,
+        None => Vec::new(),
+    };
+    let queue = Arc::new(Queue::open(backlog, run_args.jobs()));
+
+    let poller = Poller {
+        client,
+        queue: queue.clone(),
+        args: run_args.clone(),
+        db_max_age: time::Duration::hours(args.db_max_age_hours),
+        base_url: args
+            .base_url
+            .unwrap_or_else(|| default_base_url(&run_args.bucket)),
+    };
+    let interval = Duration::from_secs(args.poll_mins * 60);
+    tokio::spawn(async move {
+        // Also picks up what finished shortly before we started
+        let mut last_sync = time::OffsetDateTime::now_utc() - interval;
+        loop {
+            let started = time::OffsetDateTime::now_utc();
+            match poller.poll(started - last_sync).await {
+                Ok(()) => last_sync = started,
+                Err(e) => log::warn!("Failed to look for new versions: {:?}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+
+    run::execute(run_args, image, queue).await
+}
+
+struct Poller {
+    client: Arc<Client>,
+    queue: Arc<Queue>,
+    args: run::Args,
+    db_max_age: time::Duration,
+    base_url: String,
+}
+
+impl Poller {
+    /// Syncs what finished within `since`, then queues the versions published since the last
+    /// poll ahead of everything else.
+    async fn poll(&self, since: time::Duration) -> Result<()> {
+        let tool = self.client.tool();
+        log::info!("Syncing the crates that finished in the last {}", since);
+        sync_recent(self.client.clone(), since, &self.base_url).await?;
+
+        refresh_db(&self.client, self.db_max_age).await?;
+        let versions = self.client.get_crate_versions().await?;
+        let seen: Option<HashSet<(String, String)>> = self
+            .client
+            .download_if_exists(&tool.watched_path())
+            .await?
+            .map(|json| serde_json::from_slice(&json))
+            .transpose()?;
+        let new = match &seen {
+            Some(seen) => versions
+                .iter()
+                .filter(|c| !seen.contains(&(c.name.clone(), c.version.to_string())))
+                .cloned()
+                .collect::<Vec<Crate>>(),
+            None => {
+                log::info!("Recording the versions there are now, new ones are run from now on");
+                Vec::new()
+            }
+        };
+        // Queued before they are recorded as seen, so that the state file of a watch that is
+        // stopped has them if it didn't get to them
+        let jobs = run::jobs_for(&self.args, &self.client, new).await?;
+        log::info!("Found {} new versions to run", jobs.len());
+        self.queue.prioritize(jobs);
+
+        let seen = versions
+            .into_iter()
+            .map(|c| (c.name, c.version.to_string()))
+            .collect::<HashSet<_>>();
+        self.client
+            .upload(
+                &tool.watched_path(),
+                &serde_json::to_vec(&seen)?,
+                "application/json",
+            )
+            .await?;
+        Ok(())
+    }
+}